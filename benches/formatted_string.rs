@@ -0,0 +1,138 @@
+// Memory use and rough timings of common FormattedString operations on
+// scrollback-sized input, next to the same for a format per character (how
+// formatted text used to be stored). Run with:
+//   cargo bench --bench formatted_string
+extern crate tome;
+
+use std::mem;
+use std::time::Instant;
+use tome::{FormattedString, Format, Color};
+
+const ITERATIONS: u32 = 20000;
+const SCROLLBACK_LINES: usize = 10000;
+
+// Helper function to build a line of server output: mostly plain text with a
+// few colored words and some multibyte characters.
+fn sample_line() -> Vec<(char, Format)> {
+    let words = ["The", "goblin", "h\u{e9}site", "before", "attacking", "you",
+        "with", "a", "rusty", "\u{5263}", "(", "\u{1f5e1}", ")", "and",
+        "misses", "badly."];
+    let colors = [Color::Default, Color::Red, Color::Default, Color::Yellow];
+    let mut line = Vec::new();
+    for _ in 0..4 {
+        for (i, word) in words.iter().enumerate() {
+            let format = Format::with_fg(colors[i % colors.len()]);
+            line.extend(word.chars().map(|c| (c, format)));
+            line.push((' ', Format::default()));
+        }
+    }
+    line
+}
+
+// Helper function to build a scrollback buffer's worth of lines, the way
+// received text is built up one character at a time. Most lines from a server
+// are a single color, with the odd heavily colored one.
+fn sample_scrollback(line: &[(char, Format)]) -> Vec<FormattedString> {
+    let plain = Format::default();
+    let green = Format::with_fg(Color::Green);
+    (0..SCROLLBACK_LINES).map(|i| {
+        let mut fs = FormattedString::new();
+        for &(ch, format) in line.iter() {
+            fs.push(match i % 4 {
+                0 => (ch, format),
+                1 => (ch, green),
+                _ => (ch, plain)
+            });
+        }
+        fs
+    }).collect()
+}
+
+// Helper function to print the heap memory used by the scrollback in each
+// representation.
+fn report_memory(scrollback: &[FormattedString]) {
+    let spans: usize = scrollback.iter().map(|fs| fs.heap_size()).sum();
+    let per_char: usize = scrollback.iter()
+        .map(|fs| fs.len() * mem::size_of::<(char, Format)>())
+        .sum();
+    println!("{} lines of scrollback:", scrollback.len());
+    println!("{:<34} {:>10} bytes", "spans: heap size", spans);
+    println!("{:<34} {:>10} bytes", "per char: heap size", per_char);
+    println!("{:<34} {:>10.1}x", "reduction", per_char as f64 / spans as f64);
+    assert!(spans < per_char);
+}
+
+// Helper function to time a closure, printing the average time per run. The
+// closure returns a number derived from its result so that the work can't be
+// optimized away.
+fn bench<F: FnMut() -> usize>(name: &str, mut f: F) {
+    let mut total = 0;
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        total += f();
+    }
+    let elapsed = start.elapsed();
+    let nanos = elapsed.as_secs() * 1_000_000_000 +
+        elapsed.subsec_nanos() as u64;
+    println!("{:<34} {:>8} ns/iter (checksum {})", name,
+        nanos / ITERATIONS as u64, total);
+}
+
+fn main() {
+    let line = sample_line();
+    let fs: FormattedString = line.iter().cloned().collect();
+    let highlight = |f: Format| Format { bg_color: Color::Blue, .. f };
+
+    report_memory(&sample_scrollback(&line));
+
+    bench("spans: push chars", || {
+        let mut built = FormattedString::new();
+        for &c in line.iter() {
+            built.push(c);
+        }
+        built.len()
+    });
+    bench("per char: push chars", || {
+        let mut built = Vec::new();
+        for &c in line.iter() {
+            built.push(c);
+        }
+        built.len()
+    });
+
+    bench("spans: clone", || fs.clone().len());
+    bench("per char: clone", || line.clone().len());
+
+    bench("spans: iterate runs", || {
+        fs.spans().map(|(s, _)| s.len()).sum::<usize>()
+    });
+    bench("per char: iterate chars", || {
+        line.iter().map(|&(c, _)| c.len_utf8()).sum::<usize>()
+    });
+
+    bench("spans: split_off at last space", || {
+        let mut copy = fs.clone();
+        let index = copy.rfind(' ').unwrap();
+        copy.split_off(index).len()
+    });
+    bench("per char: split_off at last space", || {
+        let mut copy = line.clone();
+        let index = copy.iter().rposition(|&(c, _)| c == ' ').unwrap();
+        copy.split_off(index).len()
+    });
+
+    bench("spans: map_format middle", || {
+        let mut copy = fs.clone();
+        let len = copy.len();
+        copy.map_format(len / 3, 2 * len / 3, &highlight);
+        copy.len()
+    });
+    bench("per char: map_format middle", || {
+        let mut copy = line.clone();
+        let len = copy.len();
+        for &mut (_, ref mut f) in copy[len / 3..2 * len / 3].iter_mut() {
+            *f = highlight(*f);
+        }
+        copy.len()
+    });
+}
@@ -60,5 +60,88 @@
   (lambda (data)
     (tome:write-scrollback data)))
 
+; Function to run when a prompt is received from the server (marked with GA
+; or EOR, or left unfinished for a moment). The prompt is always shown on its
+; own line above the input line, so by default it isn't written to the
+; scrollback as well. Returns a list of actions to perform.
+(define prompt-hook
+  (lambda (prompt)
+    '()))
+
+; Function to run when a GMCP message is received from the server. The data is
+; converted from JSON, with objects becoming association lists, non-integer
+; numbers becoming strings (e.g. "97.5") and null becoming the symbol null.
+; Returns a list of actions to perform.
+(define gmcp-hook
+  (lambda (package data)
+    '()))
+
+; Function to run when an MSDP variable is received from the server. Tables
+; become association lists and arrays become lists. The latest value of any
+; variable can also be read with tome:msdp-get. Returns a list of actions to
+; perform.
+(define msdp-hook
+  (lambda (variable value)
+    '()))
+
+; Function to run when an MSP sound or music trigger is received from the
+; server. The kind is "sound" or "music" and the parameters are an association
+; list with "file", "volume", "loops", "priority", "continue", "type" and "url".
+; Returns a list of actions to perform; e.g. to play sounds with an external
+; player:
+;   (list (tome:run-program "paplay" (cdr (assoc "file" params))))
+(define msp-hook
+  (lambda (kind params)
+    '()))
+
+; Triggers are checked against each complete line from the server, highest
+; priority first. The callback gets the line and a list of the whole match
+; followed by each capture group (#f for groups that didn't match), and
+; returns a list of actions to perform:
+;   (tome:add-trigger name regex callback [priority [group]])
+;   (tome:remove-trigger name)
+;   (tome:enable-trigger name #t/#f)
+;   (tome:enable-trigger-group group #t/#f)
+; e.g.
+;   (tome:add-trigger "greet" "^(\\w+) arrives\\.$"
+;     (lambda (line captures)
+;       (list (tome:send (string-append "wave " (car (cdr captures))))))
+;     0 "social")
+
+; MXP links (from <send> and <a> tags) in the line being received can be
+; looked up by triggers and hooks with (tome:line-links), which gives an
+; association list for each link with "text", "href", "hint" (#f if there
+; isn't one) and "prompt".
+
+; Actions returned by triggers and hooks can also change the line being
+; received before it's written:
+;   (tome:gag)                        drop the line
+;   (tome:highlight regex codes)      format matching text with markup codes,
+;                                     e.g. "{R{*"
+;   (tome:substitute regex fstring)   replace matching text (a string or a
+;                                     formatted string)
+
+; Timers call a procedure after a delay in milliseconds, once or repeatedly,
+; and perform the list of actions it returns:
+;   (tome:add-timer name delay callback [repeating])
+;   (tome:remove-timer name)
+;   (tome:list-timers)
+; e.g.
+;   (tome:add-timer "keep-alive" 60000
+;     (lambda () (list (tome:send "look")))
+;     #t)
+
+; Keys can be bound to a procedure returning a list of actions, or to one of
+; the built-in actions: quit, prev-page, next-page, backspace-input,
+; delete-input-char, send-input, cursor-left, cursor-right, history-prev,
+; history-next, delete-to-cursor, reconnect and reload-config. Key names are
+; the terminfo names, e.g. "F1", "UP" or "PAGEUP". Bindings take effect when
+; the config is (re)loaded.
+;   (tome:bind-key key procedure-or-action-name)
+;   (tome:unbind-key key)
+; e.g.
+;   (tome:bind-key "F1" (lambda () (list (tome:send "score"))))
+;   (tome:bind-key "F5" "reload-config")
+
 ; ===== MUD-SPECIFIC STUFF =====
 (define-alias "test" "4n4e")
@@ -1,13 +1,42 @@
 use context::Context;
 use mio::tcp::TcpStream;
-use scripting::{self, ScriptAction};
+use scripting::{self, LinePattern, ScriptAction};
+use std::cmp;
 use std::fs::File;
 use std::io;
-use std::io::{Read, Write};
+use std::io::Read;
 use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
 use session::Session;
-use tome::{formatted_string, Style, Color, Format, FormattedString, RingBuffer,
-    esc_seq, search, telnet, ParseState};
+use tome::{formatted_string, Color, Format, FormattedString, RingBuffer,
+    SearchResult, esc_seq, search, telnet, ParseState};
+use tome::mccp::{Deflater, Inflater};
+use tome::msp::Trigger;
+use tome::mxp;
+use tome::encoding::{self, Encoding};
+use tome::telnet::{charset, gmcp, ttype, OptionEvent};
+use tome::telnet::gmcp::Json;
+use tome::telnet::msdp::{self, MsdpValue};
+
+// GMCP packages requested from the server once GMCP is enabled.
+const GMCP_SUPPORTS: &'static str =
+    "[ \"Core 1\", \"Char 1\", \"Char.Skills 1\", \"Char.Items 1\", \"Room 1\", \"Comm 1\" ]";
+
+// How long to wait for the rest of a line before treating it as a prompt.
+const PARTIAL_LINE_TIMEOUT_MS: u64 = 500;
+
+// Data from the server that is handed to the scripts, in the order received.
+// Lines are only handed over once they're complete, so anything else that
+// arrives partway through a line waits for the line to finish.
+pub enum ServerEvent {
+    Text(FormattedString),
+    Prompt(FormattedString),
+    Gmcp(String, Json),
+    Msdp(String, MsdpValue),
+    Sound(Trigger)
+}
 
 // Actions to be used directly for key bindings.
 pub fn quit(_: &mut Context) -> bool { false }
@@ -45,19 +74,30 @@ pub fn send_input(context: &mut Context) -> bool {
     // the contents of the input line.
     let input_line_contents = formatted_string::to_string(
         context.history.data.get_recent(context.history.index()));
-    match context.script_interface.send_hook(&input_line_contents) {
-        Ok(actions) => {
-            actions.into_iter().map(|action| do_action(&action, context)).last();
-        },
-        Err(e) => {
-            // Write the error to the scrollback buffer.
-            write_scrollback(context,
-                formatted_string::with_color(&e, Color::Red));
+    let echo_off = is_echo_off(context);
+    if echo_off {
+        // The server is asking for something secret (e.g. a password); send
+        // it as-is rather than letting the scripts see it.
+        send_data(context, &input_line_contents, true);
+    } else {
+        match context.script_interface.send_hook(&input_line_contents) {
+            Ok(actions) => {
+                actions.into_iter().map(|action| do_action(&action, context)).last();
+            },
+            Err(e) => {
+                // Write the error to the scrollback buffer.
+                write_scrollback(context,
+                    formatted_string::with_color(&e, Color::Red));
+            }
         }
     }
 
     // Add the input to the history and clear the input line.
-    if context.history.index() > 0 {
+    if echo_off {
+        // Keep secrets out of the history.
+        context.history.reset_index();
+        context.history.data.get_recent_mut(0).clear();
+    } else if context.history.index() > 0 {
         // History has been scrolled back and needs to be reset.
         context.history.reset_index();
         context.history.data.get_recent_mut(0).clear();
@@ -65,7 +105,8 @@ pub fn send_input(context: &mut Context) -> bool {
             &mut context.history.data,
             formatted_string::with_format(
                 &format!("{}\n", &input_line_contents),
-                Format::default()));
+                Format::default()),
+            &[]);
     } else {
         // Input line already contains the right data; just move
         // to the next line.
@@ -88,20 +129,62 @@ fn do_action(action: &ScriptAction, context: &mut Context) {
         &ScriptAction::SendInput(ref s) => {
             send_data(context, &s, true);
 
-            // Add to the scrollback buffer.
-            write_scrollback(context,
-                formatted_string::with_color(
-                    &format!("{}\n", &s),
-                    Color::Yellow));
+            // Add to the scrollback buffer, unless the server has asked for
+            // the input to be hidden.
+            if !is_echo_off(context) {
+                write_scrollback(context,
+                    formatted_string::with_color(
+                        &format!("{}\n", &s),
+                        Color::Yellow));
+            }
         },
         &ScriptAction::Reconnect => {
             reconnect(context);
         },
         &ScriptAction::SearchBackwards(ref s) => {
             search_backwards(context, s)
+        },
+        &ScriptAction::SendGmcp(ref package, ref payload) => {
+            context.current_session_mut().write(
+                &gmcp::message(package, payload));
+        },
+        &ScriptAction::SendMsdp(ref command, ref args) => {
+            context.current_session_mut().write(&msdp::command(command, args));
+        },
+        &ScriptAction::RunProgram(ref program, ref args) => {
+            run_program(context, program, args);
+        },
+        &ScriptAction::GagLine |
+        &ScriptAction::HighlightLine(..) |
+        &ScriptAction::SubstituteLine(..) => {
+            // These only mean something for a line being received, and are
+            // applied as it's stored (see receive_text()).
         }
     }
 }
+// Helper function to start an external program without waiting for it to
+// finish. Its output is discarded so that it doesn't disturb the UI.
+fn run_program(context: &mut Context, program: &str, args: &[String]) {
+    let child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+    match child {
+        Ok(mut c) => {
+            // Reap the process once it exits.
+            thread::spawn(move || c.wait());
+        },
+        Err(e) => write_scrollback(context, formatted_string::with_color(
+            &format!("Error: failed to run {}: {}\n", program, e), Color::Red))
+    }
+}
+// Helper function to check whether the server has taken over echoing input,
+// which it does to hide passwords.
+pub fn is_echo_off(context: &Context) -> bool {
+    context.current_session().options.is_remote_enabled(telnet::ECHO)
+}
 pub fn cursor_left(context: &mut Context) -> bool {
     let cursor = context.cursor_index;
     if cursor > 0 {
@@ -135,7 +218,7 @@ pub fn delete_to_cursor(context: &mut Context) -> bool {
     let curr_line = context.history.data.get_recent_mut(history_index);
     let after_cursor = curr_line.split_off(context.cursor_index);
     curr_line.clear();
-    curr_line.extend(after_cursor);
+    curr_line.append(after_cursor);
     context.cursor_index = 0;
     true
 }
@@ -143,7 +226,12 @@ pub fn reconnect(context: &mut Context) -> bool {
     match context.current_session().connection.peer_addr() {
         Ok(sa) => {
             match TcpStream::connect(&sa) {
-                Ok(conn) => context.current_session_mut().connection = conn,
+                Ok(conn) => {
+                    // Options, compression and so on are negotiated again
+                    // with the new connection.
+                    context.current_session_mut().reset(conn);
+                    context.script_interface.clear_msdp_variables();
+                },
                 Err(_) => () // TODO: Log this error.
             }
         },
@@ -154,6 +242,12 @@ pub fn reconnect(context: &mut Context) -> bool {
 pub fn reload_config(context: &mut Context) -> bool {
     // Read the config file (if it exists).
     context.script_interface = scripting::init_interface();
+
+    // Let the new interface know about any MSDP variables already received.
+    let session = &context.sessions[context.session_index];
+    for (variable, value) in session.msdp_variables.iter() {
+        context.script_interface.set_msdp_variable(variable, value);
+    }
     match read_file_contents(&context.config_filepath) {
         Ok(contents) => {
             if let Err(e) = context.script_interface.evaluate(&contents) {
@@ -170,6 +264,12 @@ pub fn reload_config(context: &mut Context) -> bool {
                     Color::Yellow));
         }
     }
+
+    // Pick up any key bindings made by the config.
+    for e in context.rebuild_bindings() {
+        write_scrollback(context, formatted_string::with_color(
+            &format!("Warning: {}\n", e), Color::Yellow));
+    }
     true
 }
 // Helper function to read a file's contents.
@@ -180,29 +280,107 @@ fn read_file_contents(filepath: &PathBuf) -> io::Result<String> {
     Ok(file_contents)
 }
 
+// Gets one of the actions to be used directly for key bindings by the name
+// scripts use for it.
+pub fn get_builtin(name: &str) -> Option<fn(&mut Context) -> bool> {
+    let action: fn(&mut Context) -> bool = match name {
+        "quit" => quit,
+        "prev-page" => prev_page,
+        "next-page" => next_page,
+        "backspace-input" => backspace_input,
+        "delete-input-char" => delete_input_char,
+        "send-input" => send_input,
+        "cursor-left" => cursor_left,
+        "cursor-right" => cursor_right,
+        "history-prev" => history_prev,
+        "history-next" => history_next,
+        "delete-to-cursor" => delete_to_cursor,
+        "reconnect" => reconnect,
+        "reload-config" => reload_config,
+        _ => return None
+    };
+    Some(action)
+}
+
 // Actions with arguments.
 pub fn write_scrollback(context: &mut Context, data: FormattedString) {
-    write_to_line_buffer(
-        &mut context.current_session_mut().scrollback_buf.data,
-        data);
+    let session = context.current_session_mut();
+    write_to_line_buffer(&mut session.scrollback_buf.data, data, &[]);
+}
+// Helper function to write a line received from the server to the
+// scrollback, applying any gag, highlight and substitute actions for it.
+fn write_received_line(context: &mut Context, data: FormattedString,
+    edits: &[ScriptAction])
+{
+    let session = context.current_session_mut();
+    write_to_line_buffer(&mut session.scrollback_buf.data, data, edits);
 }
-// Helper function to handle writing to buffers while being line-aware.
+// Helper function to handle writing to buffers while being line-aware. The
+// edits are applied to each line as it's completed.
 fn write_to_line_buffer(buffer: &mut RingBuffer<FormattedString>,
-    data: FormattedString)
+    data: FormattedString, edits: &[ScriptAction])
 {
     for (ch, format) in data {
         match ch {
             '\r' => (),
-            '\n' => buffer.push(FormattedString::new()),
+            '\n' => {
+                // A gagged line is cleared and reused for the next line.
+                if edit_line(buffer.get_recent_mut(0), edits) {
+                    buffer.push(FormattedString::new());
+                }
+            },
             _ => buffer.get_recent_mut(0).push((ch, format))
         }
     }
 }
+// Helper function to apply gag, highlight and substitute actions to a line.
+// Returns false if the line was gagged.
+fn edit_line(line: &mut FormattedString, edits: &[ScriptAction]) -> bool {
+    for edit in edits {
+        match *edit {
+            ScriptAction::GagLine => {
+                line.clear();
+                return false;
+            },
+            ScriptAction::HighlightLine(LinePattern(ref regex), format) => {
+                for (start, end) in line.find_all_regex(regex) {
+                    // Keep any link so that it still works.
+                    line.map_format(start, end,
+                        |f| Format { link: f.link, ..format });
+                }
+            },
+            ScriptAction::SubstituteLine(LinePattern(ref regex), ref replacement) => {
+                let mut new_line = FormattedString::new();
+                let mut prev_end = 0;
+                for (start, end) in line.find_all_regex(regex) {
+                    new_line.append(line.slice(prev_end, start));
+                    new_line.append(replacement.clone());
+                    prev_end = end;
+                }
+                new_line.append(line.slice(prev_end, line.len()));
+                *line = new_line;
+            },
+            _ => ()
+        }
+    }
+    true
+}
 pub fn send_data(context: &mut Context, data: &str, add_line_ending: bool) {
-    // TODO: Check result.
     let data_to_send = format!("{}{}", data,
         if add_line_ending {"\r\n"} else {""});
-    context.current_session_mut().connection.write(data_to_send.as_bytes());
+    let session = context.current_session_mut();
+    let bytes = encoding::encode(&data_to_send, session.decoder.encoding());
+    let binary = session.options.is_local_enabled(telnet::BINARY);
+    session.write(&telnet::encode(&bytes, binary));
+}
+pub fn set_window_size(context: &mut Context, width: usize, height: usize) {
+    let size = (width as u16, height as u16);
+    for session in context.sessions.iter_mut() {
+        session.window_size = size;
+        if session.options.is_local_enabled(telnet::NAWS) {
+            session.write(&telnet::naws(size.0, size.1));
+        }
+    }
 }
 pub fn insert_input_char(context: &mut Context, ch: char) {
     let hist_index = context.history.index();
@@ -213,8 +391,10 @@ pub fn insert_input_char(context: &mut Context, ch: char) {
 pub fn search_backwards(context: &mut Context, search_str: &str) {
     let viewport_lines = context.viewport_lines;
     let sess = context.current_session_mut();
-    let start_line = match sess.prev_search_result {
-        Some(p) => p.line_number + 1,
+    let prev_line = sess.prev_search_result
+        .and_then(|r| search_result_line(&sess.scrollback_buf.data, r));
+    let start_line = match prev_line {
+        Some(line_number) => line_number + 1,
         None => 0
     };
     let this_result = match search::search_buffer(
@@ -225,9 +405,9 @@ pub fn search_backwards(context: &mut Context, search_str: &str) {
     };
 
     // Un-highlight the old search result if there is one.
-    if let Some(r) = sess.prev_search_result {
-        let line = sess.scrollback_buf.data.get_recent_mut(r.line_number);
-        highlight_string(line, r.begin_index, r.end_index, false);
+    if let (Some(r), Some(line_number)) = (sess.prev_search_result, prev_line) {
+        let line = sess.scrollback_buf.data.get_recent_mut(line_number);
+        toggle_highlight(line, r.begin_index, r.end_index);
     }
 
     // Highlight the new search result.
@@ -236,23 +416,185 @@ pub fn search_backwards(context: &mut Context, search_str: &str) {
             else {r.line_number - viewport_lines + 1};
         sess.scrollback_buf.set_index(line_number);
         let line = sess.scrollback_buf.data.get_recent_mut(r.line_number);
-        highlight_string(line, r.begin_index, r.end_index, true);
+        toggle_highlight(line, r.begin_index, r.end_index);
     }
 
     // Store the new search result.
     sess.prev_search_result = this_result;
 }
-fn highlight_string(s: &mut FormattedString, start: usize, end: usize, on: bool) {
-    for i in start..end {
-        let (ch, format) = s[i];
-        let mut new_format = format.clone();
-        new_format.style = if on {Style::Standout} else {Style::Normal};
-        s[i] = (ch, new_format);
-    }
+// Helper function to find a search result's line now, counting back from the
+// most recent line, since more lines may have arrived after the search. Gives
+// None if the line has since been dropped from the buffer.
+fn search_result_line(buffer: &RingBuffer<FormattedString>, r: SearchResult) ->
+    Option<usize>
+{
+    let line_number = r.line_number + buffer.total_pushed() - r.total_lines;
+    if line_number < buffer.len() {Some(line_number)} else {None}
+}
+// Helper function to highlight a search result by flipping reverse video.
+// Doing it again restores the original formats, including any reverse video
+// from the server.
+fn toggle_highlight(s: &mut FormattedString, start: usize, end: usize) {
+    s.map_format(start, end, |format| {
+        let mut new_format = format;
+        new_format.style.reverse = !format.style.reverse;
+        new_format
+    });
 }
 pub fn receive_data(context: &mut Context, data: &[u8]) {
-    let string = handle_server_data(data, context.current_session_mut());
-    match context.script_interface.recv_hook(&string) {
+    let events = handle_socket_data(data, context.current_session_mut());
+    receive_data_events(context, events);
+}
+// Helper function to handle the events from data received from the server.
+fn receive_data_events(context: &mut Context, events: Vec<ServerEvent>) {
+    for event in events {
+        let result = match event {
+            ServerEvent::Text(ref string) => {
+                // The text is made of complete lines. Handle one at a time so
+                // that triggers can change the line they match.
+                let mut rest = string.clone();
+                while let Some(i) = rest.as_str().chars().position(|ch| ch == '\n') {
+                    let after = rest.split_off(i + 1);
+                    receive_text(context, &rest, false);
+                    rest = after;
+                }
+                continue;
+            },
+            ServerEvent::Prompt(ref prompt) => {
+                // Keep the prompt around for display.
+                context.current_session_mut().prompt = Some(prompt.iter()
+                    .filter(|&(ch, _)| ch != '\r' && ch != '\n')
+                    .collect());
+                receive_text(context, prompt, true);
+                continue;
+            },
+            ServerEvent::Gmcp(ref package, ref data) =>
+                context.script_interface.gmcp_hook(package, data),
+            ServerEvent::Msdp(ref variable, ref value) =>
+                context.script_interface.msdp_hook(variable, value),
+            ServerEvent::Sound(ref trigger) =>
+                context.script_interface.msp_hook(trigger)
+        };
+        do_script_actions(context, result);
+    }
+}
+// Flushes a partial line as a prompt if nothing more has arrived for it within
+// the timeout. Some servers don't mark their prompts with GA or EOR. Returns
+// whether there was anything to flush.
+pub fn flush_partial_line(context: &mut Context) -> bool {
+    let events = take_timed_out_line(context.current_session_mut());
+    if events.is_empty() {
+        return false;
+    }
+    receive_data_events(context, events);
+    true
+}
+// Helper function to take a partial line that has timed out, along with any
+// events waiting for it.
+fn take_timed_out_line(session: &mut Session) -> Vec<ServerEvent> {
+    match session.partial_line_time {
+        Some(t) if t.elapsed() >= Duration::from_millis(PARTIAL_LINE_TIMEOUT_MS) => {
+            session.partial_line_time = None;
+            let mut prompt = session.partial_line.split_off(0);
+            finish_msp(session, &mut prompt);
+            let mut events = vec![ServerEvent::Prompt(prompt)];
+            events.extend(session.held_events.drain(..));
+            events
+        },
+        _ => vec![]
+    }
+}
+// Runs the actions from any timers that have gone off. Returns whether any
+// did.
+pub fn run_timers(context: &mut Context) -> bool {
+    let (actions, errors) = context.script_interface.run_timers();
+    let ran = !actions.is_empty() || !errors.is_empty();
+    do_script_actions(context, Ok(actions));
+    for e in errors.into_iter() {
+        do_script_actions(context, Err(e));
+    }
+    ran
+}
+// Gets how long the polling loop can wait before a partial line needs to be
+// flushed or a timer goes off.
+pub fn poll_timeout(context: &Context) -> Option<Duration> {
+    let partial_line_timeout = context.current_session().partial_line_time.map(|t| {
+        let timeout = Duration::from_millis(PARTIAL_LINE_TIMEOUT_MS);
+        let elapsed = t.elapsed();
+        if elapsed >= timeout {Duration::from_millis(0)} else {timeout - elapsed}
+    });
+    let timer_timeout = context.script_interface.time_until_next_timer();
+    match (partial_line_timeout, timer_timeout) {
+        (Some(a), Some(b)) => Some(cmp::min(a, b)),
+        (a, b) => a.or(b)
+    }
+}
+// Helper function to pass a received line to the triggers and hooks. Triggers
+// run first so that their line edits apply when the line is written, but their
+// other actions run afterwards so that any output follows the line.
+fn receive_text(context: &mut Context, text: &FormattedString, is_prompt: bool) {
+    let links = line_links(context.current_session(), text);
+    context.script_interface.set_line_links(links);
+    let (actions, errors) = run_triggers(context, text);
+    let (mut edits, actions): (Vec<_>, Vec<_>) = actions.into_iter()
+        .partition(|a| a.is_line_edit());
+    let result = if is_prompt {
+        context.script_interface.prompt_hook(text)
+    } else {
+        context.script_interface.recv_hook(text)
+    };
+    match result {
+        Ok(hook_actions) => {
+            // The edits from the triggers and the hook all apply to the line,
+            // which the hook stores by writing it to the scrollback. Anything
+            // else the hook does is left alone.
+            let (hook_edits, hook_actions): (Vec<_>, Vec<_>) = hook_actions
+                .into_iter().partition(|a| a.is_line_edit());
+            edits.extend(hook_edits);
+            for action in hook_actions {
+                match action {
+                    ScriptAction::WriteScrollback(data) =>
+                        write_received_line(context, data, &edits),
+                    _ => do_action(&action, context)
+                }
+            }
+        },
+        Err(e) => do_script_actions(context, Err(e))
+    }
+    do_script_actions(context, Ok(actions));
+    for e in errors.into_iter() {
+        do_script_actions(context, Err(e));
+    }
+}
+// Helper function to find the MXP links in a line, along with their text.
+fn line_links(session: &Session, line: &FormattedString) -> Vec<(String, mxp::Link)> {
+    let mut links: Vec<(usize, String)> = Vec::new();
+    for (text, format) in line.spans() {
+        if let Some(id) = format.link {
+            match links.iter().position(|&(i, _)| i == id) {
+                Some(n) => links[n].1.push_str(text),
+                None => links.push((id, text.to_string()))
+            }
+        }
+    }
+    links.into_iter()
+        .filter_map(|(id, text)| session.links.get(id).map(|l| (text, l.clone())))
+        .collect()
+}
+// Helper function to run triggers against a received line.
+fn run_triggers(context: &mut Context, line: &FormattedString) ->
+    (Vec<ScriptAction>, Vec<String>)
+{
+    let line: String = line.as_str().chars()
+        .filter(|&ch| ch != '\r' && ch != '\n')
+        .collect();
+    context.script_interface.run_triggers(&line)
+}
+// Helper function to run the actions returned by a script hook.
+pub fn do_script_actions(context: &mut Context,
+    result: Result<Vec<ScriptAction>, String>)
+{
+    match result {
         Ok(actions) => {
             actions.into_iter().map(|action| do_action(&action, context)).last();
         },
@@ -263,75 +605,690 @@ pub fn receive_data(context: &mut Context, data: &[u8]) {
         }
     }
 }
-// Helper function to deal with incoming data from the server.
-fn handle_server_data(data: &[u8], session: &mut Session) -> FormattedString {
-    let mut out_str = FormattedString::new();
-    for byte in data {
+// Helper function to decompress incoming data from the server (if needed)
+// before handing it to the telnet layer.
+fn handle_socket_data(data: &[u8], session: &mut Session) -> Vec<ServerEvent> {
+    let mut events = Vec::new();
+    let old_partial_line = session.partial_line.clone();
+    let mut out_str = session.partial_line.split_off(0);
+    let mut remaining = data;
+    while remaining.len() > 0 {
+        let inflated = match session.inflater {
+            Some(ref mut i) => Some(i.inflate(remaining)),
+            None => None
+        };
+        match inflated {
+            None => {
+                // Compression may start partway through the data.
+                let consumed = handle_server_data(remaining, session,
+                    &mut out_str, &mut events);
+                remaining = &remaining[consumed..];
+            },
+            Some(Ok((decompressed, stream_end))) => {
+                handle_server_data(&decompressed, session, &mut out_str,
+                    &mut events);
+                match stream_end {
+                    Some(consumed) => {
+                        // The server ended compression; the rest of the data
+                        // is uncompressed.
+                        info!("Compressed stream ended");
+                        session.inflater = None;
+                        remaining = &remaining[consumed..];
+                    },
+                    None => break
+                }
+            },
+            Some(Err(e)) => {
+                // The rest of the stream can't be trusted; ask the server to
+                // stop compressing.
+                warn!("Compressed stream is corrupt: {}", e);
+                session.inflater = None;
+                if let Some(r) = session.options.disable_remote(telnet::COMPRESS2) {
+                    session.write(&r);
+                }
+                out_str.append(formatted_string::with_color(
+                    &format!("\nError: compressed stream is corrupt ({})\n", e),
+                    Color::Red));
+                break;
+            }
+        }
+    }
+
+    // Hold on to any partial line until the rest of it arrives. The timeout
+    // for flushing it only starts over when more of it has arrived.
+    flush_text(session, &mut out_str, &mut events);
+    if out_str.is_empty() && !session.msp.has_pending() {
+        session.partial_line_time = None;
+    } else if out_str != old_partial_line || session.partial_line_time.is_none() {
+        session.partial_line_time = Some(Instant::now());
+    }
+    session.partial_line = out_str;
+    events
+}
+// Helper function to deal with incoming data from the server. Returns the
+// number of bytes handled, which is less than the length of the data if
+// compression was started partway through.
+fn handle_server_data(data: &[u8], session: &mut Session,
+    out_str: &mut FormattedString, events: &mut Vec<ServerEvent>) -> usize
+{
+    let compressing = session.inflater.is_some();
+    let mut chars = Vec::new();
+    for (i, byte) in data.iter().enumerate() {
         // Apply the telnet layer.
         let new_telnet_state = telnet::parse(&session.telnet_state, *byte);
         match new_telnet_state {
             ParseState::NotInProgress => {
-                // Apply the esc sequence layer.
-                let new_esc_seq_state =
-                    esc_seq::parse(&session.esc_seq_state, *byte);
-                match new_esc_seq_state {
-                    ParseState::NotInProgress => {
-                        // TODO: Properly convert to UTF-8.
-                        out_str.push((*byte as char, session.char_format));
-                    },
-                    ParseState::InProgress(_) => (),
-                    ParseState::Success(ref seq) => {
-                        handle_esc_seq(&seq, session);
-                    },
-                    ParseState::Error(ref bad_seq) => {
-                        warn!("Bad escape sequence encountered: {:?}", bad_seq);
-                    }
-                }
-                session.esc_seq_state = new_esc_seq_state;
+                handle_text_byte(*byte, session, out_str, &mut chars, events);
             },
             ParseState::InProgress(_) => (),
+            ParseState::Success(ref cmd) if &cmd[..] == &[telnet::IAC, telnet::IAC] => {
+                // An escaped IAC is just data.
+                handle_text_byte(telnet::IAC, session, out_str, &mut chars,
+                    events);
+            },
             ParseState::Success(ref cmd) => {
                 info!("Telnet command encountered: {:?}", cmd);
-                handle_telnet_cmd(&cmd, session);
+
+                // Keep the text that arrived before the command ahead of any
+                // events it produces.
+                if cmd.len() == 2 && (cmd[1] == telnet::GA || cmd[1] == telnet::EOR) {
+                    // Everything since the last newline is a prompt.
+                    finish_msp(session, out_str);
+                    let prompt_start = match out_str.rfind('\n') {
+                        Some(i) => i + 1,
+                        None => 0
+                    };
+                    let prompt = out_str.split_off(prompt_start);
+                    flush_text(session, out_str, events);
+                    events.push(ServerEvent::Prompt(prompt));
+                    events.extend(session.held_events.drain(..));
+                } else {
+                    flush_text(session, out_str, events);
+                    let mut cmd_events = Vec::new();
+                    handle_telnet_cmd(&cmd, session, &mut cmd_events);
+                    for event in cmd_events {
+                        push_event(session, out_str, events, event);
+                    }
+                }
             },
             ParseState::Error(ref bad_cmd) => {
                 warn!("Bad telnet command encountered: {:?}", bad_cmd);
             }
         }
         session.telnet_state = new_telnet_state;
+
+        if !compressing && session.inflater.is_some() {
+            return i + 1;
+        }
     }
 
-    out_str
+    data.len()
+}
+// Helper function to deal with a byte that isn't part of a telnet command.
+fn handle_text_byte(byte: u8, session: &mut Session,
+    out_str: &mut FormattedString, chars: &mut Vec<char>,
+    events: &mut Vec<ServerEvent>)
+{
+    // Apply the esc sequence layer.
+    let new_esc_seq_state = esc_seq::parse(&session.esc_seq_state, byte);
+    match new_esc_seq_state {
+        ParseState::NotInProgress => {
+            // Multi-byte characters may take a few bytes to complete.
+            session.decoder.decode(byte, chars);
+
+            // Pull out any sound triggers before the text is displayed. Text
+            // is only checked for them once the server has agreed to MSP,
+            // since anyone who can send a line could write one otherwise.
+            let msp_enabled = session.options.is_remote_enabled(telnet::MSP);
+            let mut text = Vec::new();
+            if !msp_enabled {
+                session.msp.finish(&mut text);
+            }
+            for ch in chars.drain(..) {
+                let format = session.char_format;
+                if !msp_enabled {
+                    text.push((ch, format));
+                } else if let Some(trigger) = session.msp.parse(ch, format, &mut text) {
+                    output_text(session, text.split_off(0), out_str);
+                    flush_text(session, out_str, events);
+                    push_event(session, out_str, events,
+                        ServerEvent::Sound(trigger));
+                }
+            }
+            output_text(session, text, out_str);
+        },
+        ParseState::InProgress(_) => (),
+        ParseState::Success(ref seq) => {
+            handle_esc_seq(&seq, session);
+        },
+        ParseState::Error(ref bad_seq) => {
+            warn!("Bad escape sequence encountered: {:?}", bad_seq);
+            if esc_seq::interrupted_by_control(bad_seq) {
+                // The control character (e.g. a newline) still counts.
+                session.esc_seq_state = ParseState::NotInProgress;
+                handle_text_byte(byte, session, out_str, chars, events);
+                return;
+            }
+        }
+    }
+    session.esc_seq_state = new_esc_seq_state;
 }
-fn handle_telnet_cmd(cmd: &[u8], session: &mut Session) {
-    // TODO: Implement this.
-    if cmd.len() == 3 && &cmd[..3] == &[telnet::IAC, telnet::WILL, telnet::GMCP] {
-        info!("IAC WILL GMCP received");
-        session.connection.write(&[telnet::IAC, telnet::DO, telnet::GMCP]);
+// Helper function to add text to the output, applying MXP if it's enabled.
+fn output_text(session: &mut Session, text: Vec<(char, Format)>,
+    out_str: &mut FormattedString)
+{
+    let mxp_enabled = session.options.is_remote_enabled(telnet::MXP);
+    for (ch, format) in text {
+        if mxp_enabled {
+            session.mxp.parse(ch, format, out_str);
+        } else {
+            out_str.push((ch, format));
+        }
+    }
+    for (id, link) in session.mxp.take_links() {
+        session.links.insert(id, link);
+    }
+}
+// Helper function to output any text held back as the possible start of a
+// sound trigger, since the line it's on has ended.
+fn finish_msp(session: &mut Session, out_str: &mut FormattedString) {
+    let mut text = Vec::new();
+    session.msp.finish(&mut text);
+    output_text(session, text, out_str);
+}
+// Helper function to move the complete lines received so far into the list of
+// events. Any partial line is left behind. Events held back by the first line
+// go right after it.
+fn flush_text(session: &mut Session, out_str: &mut FormattedString,
+    events: &mut Vec<ServerEvent>)
+{
+    if !session.held_events.is_empty() {
+        if let Some(i) = out_str.as_str().chars().position(|ch| ch == '\n') {
+            let rest = out_str.split_off(i + 1);
+            events.push(ServerEvent::Text(out_str.split_off(0)));
+            events.extend(session.held_events.drain(..));
+            *out_str = rest;
+        }
+    }
+    if let Some(i) = out_str.rfind('\n') {
+        let partial_line = out_str.split_off(i + 1);
+        events.push(ServerEvent::Text(out_str.split_off(0)));
+        *out_str = partial_line;
+    }
+}
+// Helper function to add an event that arrived after the text in out_str. If
+// that text ends partway through a line, the event waits for the line.
+fn push_event(session: &mut Session, out_str: &FormattedString,
+    events: &mut Vec<ServerEvent>, event: ServerEvent)
+{
+    if out_str.is_empty() {
+        events.push(event);
+    } else {
+        session.held_events.push(event);
+    }
+}
+fn handle_telnet_cmd(cmd: &[u8], session: &mut Session,
+    events: &mut Vec<ServerEvent>)
+{
+    // Handle option negotiation.
+    if cmd.len() == 3 {
+        let (reply, event) = session.options.receive(cmd[1], cmd[2]);
+        if let Some(r) = reply {
+            session.write(&r);
+        }
+        if let Some(e) = event {
+            info!("Telnet option state changed: {:?}", e);
+            handle_option_event(e, session);
+        }
+        return;
     }
 
-    if cmd.len() > 3 && &cmd[..3] == &[telnet::IAC, telnet::SB, telnet::GMCP] {
-        let mid: Vec<u8> = (&cmd[3..cmd.len() - 2])
-            .iter()
-            .map(|b| *b)
-            .collect();
-        let mid_str = match String::from_utf8(mid) {
-            Ok(m) => m,
-            Err(_) => return
-        };
-        info!("Received GMCP message: {}", &mid_str);
+    // Handle subnegotiation.
+    match telnet::parse_subnegotiation(cmd) {
+        Some((telnet::TTYPE, ref data)) if data.first() == Some(&ttype::SEND) => {
+            let response = session.terminal_types.next();
+            session.write(&response);
+        },
+        Some((telnet::CHARSET, ref data)) if data.first() == Some(&charset::REQUEST) => {
+            let names = charset::parse_request(&data[1..]);
+            match charset::choose(&names) {
+                Some((name, encoding)) => {
+                    info!("Switching to character set {}", &name);
+                    session.decoder.set_encoding(encoding);
+                    session.write(&charset::accepted(&name));
+                },
+                None => {
+                    info!("Rejecting character sets {:?}", &names);
+                    session.write(&charset::rejected());
+                }
+            }
+        },
+        Some((telnet::CHARSET, ref data)) if data.first() == Some(&charset::ACCEPTED) => {
+            // The server agreed to one of the character sets we asked for.
+            let name = String::from_utf8_lossy(&data[1..]).into_owned();
+            match Encoding::from_name(&name) {
+                Some(encoding) => {
+                    info!("Switching to character set {}", &name);
+                    session.decoder.set_encoding(encoding);
+                },
+                None => warn!("Server accepted unknown character set {}", &name)
+            }
+        },
+        Some((telnet::CHARSET, ref data)) if data.first() == Some(&charset::REJECTED) => {
+            info!("Server rejected our character sets");
+        },
+        Some((telnet::COMPRESS2, _)) => {
+            // Everything after this command is compressed.
+            if session.options.is_remote_enabled(telnet::COMPRESS2) &&
+                session.inflater.is_none()
+            {
+                info!("Starting MCCP2 decompression");
+                session.inflater = Some(Inflater::new());
+            }
+        },
+        Some((telnet::GMCP, ref data)) => {
+            match gmcp::parse(data) {
+                Ok((package, json)) => {
+                    info!("Received GMCP message: {} {}", &package, &json);
+                    events.push(ServerEvent::Gmcp(package, json));
+                },
+                Err(e) => warn!("{}", e)
+            }
+        },
+        Some((telnet::MSDP, ref data)) => {
+            match msdp::parse(data) {
+                Ok(variables) => {
+                    for (variable, value) in variables {
+                        info!("Received MSDP variable: {} {:?}", &variable, &value);
+                        session.msdp_variables.insert(variable.clone(), value.clone());
+                        events.push(ServerEvent::Msdp(variable, value));
+                    }
+                },
+                Err(e) => warn!("Bad MSDP message: {}", e)
+            }
+        },
+        _ => ()
+    }
+}
+fn handle_option_event(event: OptionEvent, session: &mut Session) {
+    match event {
+        OptionEvent::LocalEnabled(telnet::NAWS) => {
+            let (width, height) = session.window_size;
+            session.write(&telnet::naws(width, height));
+        },
+        OptionEvent::LocalEnabled(telnet::TTYPE) => {
+            session.terminal_types.reset();
+        },
+        OptionEvent::LocalEnabled(telnet::CHARSET) |
+        OptionEvent::RemoteEnabled(telnet::CHARSET) => {
+            // Let the server know we speak UTF-8. Only ask once, when the
+            // first side agrees to negotiate.
+            let both_enabled = session.options.is_local_enabled(telnet::CHARSET) &&
+                session.options.is_remote_enabled(telnet::CHARSET);
+            if !both_enabled {
+                session.write(&charset::request(&["UTF-8"]));
+            }
+        },
+        OptionEvent::RemoteEnabled(telnet::GMCP) => {
+            // Introduce ourselves and list the packages we care about.
+            session.write(&gmcp::message("Core.Hello",
+                &format!("{{ \"client\": \"tome\", \"version\": \"{}\" }}",
+                    env!("CARGO_PKG_VERSION"))));
+            session.write(&gmcp::message("Core.Supports.Set", GMCP_SUPPORTS));
+        },
+        OptionEvent::RemoteEnabled(telnet::COMPRESS3) => {
+            // Let the server know that everything after this is compressed.
+            session.write(&telnet::subnegotiation(telnet::COMPRESS3, &[]));
+            session.deflater = Some(Deflater::new());
+        },
+        OptionEvent::RemoteDisabled(telnet::COMPRESS3) => {
+            session.deflater = None;
+        },
+        _ => ()
     }
 }
 fn handle_esc_seq(seq: &[u8], session: &mut Session) {
+    // MXP line mode changes share the escape sequence syntax.
+    if let Some(mode) = mxp::line_mode(seq) {
+        session.mxp.set_line_mode(mode);
+        return;
+    }
+
+    // Cursor movement, erasing, window titles, etc. don't apply to the
+    // scrollback, so only colors and styles are used.
+    if !esc_seq::is_sgr(seq) {
+        info!("Ignoring escape sequence: {:?}", seq);
+        return;
+    }
+
     // Use the esc sequence to update the char format for the session.
-    let (style, fg_color, bg_color) = esc_seq::interpret(seq);
-    if let Some(s) = style {
-        session.char_format.style = s;
+    session.char_format = esc_seq::interpret(seq, session.char_format);
+}
+
+#[cfg(test)]
+mod tests {
+    use indexed::Indexed;
+    use regex::Regex;
+    use scripting::{KeyBinding, ScriptInterface};
+    use std::collections::HashMap;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::time::{Duration, Instant};
+    use super::*;
+    use tome::mxp::Link;
+
+    // Stands in for the scripts, answering with fixed actions.
+    struct TestScripts {
+        // Actions from the recv-hook after it writes the line.
+        recv_actions: Vec<ScriptAction>,
+        trigger_actions: Vec<ScriptAction>
     }
-    if let Some(f) = fg_color {
-        session.char_format.fg_color = f;
+
+    impl ScriptInterface for TestScripts {
+        fn send_hook(&mut self, _: &str) -> Result<Vec<ScriptAction>, String> {
+            Ok(vec![])
+        }
+        fn recv_hook(&mut self, data: &FormattedString) ->
+            Result<Vec<ScriptAction>, String>
+        {
+            let mut actions = vec![ScriptAction::WriteScrollback(data.clone())];
+            actions.extend(self.recv_actions.iter().cloned());
+            Ok(actions)
+        }
+        fn prompt_hook(&mut self, _: &FormattedString) ->
+            Result<Vec<ScriptAction>, String>
+        {
+            Ok(vec![])
+        }
+        fn gmcp_hook(&mut self, _: &str, _: &Json) ->
+            Result<Vec<ScriptAction>, String>
+        {
+            Ok(vec![])
+        }
+        fn msdp_hook(&mut self, _: &str, _: &MsdpValue) ->
+            Result<Vec<ScriptAction>, String>
+        {
+            Ok(vec![])
+        }
+        fn msp_hook(&mut self, _: &Trigger) -> Result<Vec<ScriptAction>, String> {
+            Ok(vec![])
+        }
+        fn run_triggers(&mut self, _: &str) -> (Vec<ScriptAction>, Vec<String>) {
+            (self.trigger_actions.clone(), vec![])
+        }
+        fn run_timers(&mut self) -> (Vec<ScriptAction>, Vec<String>) {
+            (vec![], vec![])
+        }
+        fn time_until_next_timer(&self) -> Option<Duration> { None }
+        fn key_bindings(&self) -> Vec<(String, KeyBinding)> { vec![] }
+        fn run_key_binding(&mut self, _: &str) -> Result<Vec<ScriptAction>, String> {
+            Ok(vec![])
+        }
+        fn set_msdp_variable(&mut self, _: &str, _: &MsdpValue) {}
+        fn set_line_links(&mut self, _: Vec<(String, Link)>) {}
+        fn clear_msdp_variables(&mut self) {}
+        fn evaluate(&mut self, _: &str) -> Result<(), String> { Ok(()) }
+    }
+
+    // Helper function to make a context with a single session and the given
+    // script actions.
+    fn context(scripts: TestScripts) -> Context {
+        Context {
+            sessions: vec![session()],
+            session_index: 0,
+            bindings: HashMap::new(),
+            key_codes_to_names: HashMap::new(),
+            key_names_to_codes: HashMap::new(),
+            history: Indexed::new(RingBuffer::new(None), |_| 0),
+            cursor_index: 0,
+            script_interface: Box::new(scripts),
+            config_filepath: PathBuf::new(),
+            viewport_lines: 10
+        }
+    }
+
+    // Helper function to get the complete lines in the scrollback.
+    fn scrollback(context: &Context) -> Vec<FormattedString> {
+        let buffer = &context.current_session().scrollback_buf.data;
+        (1..buffer.len()).rev().map(|i| buffer.get_recent(i).clone()).collect()
+    }
+
+    // Helper function to make a session for feeding data to. Nothing is
+    // listening by the time anything is sent, so replies are dropped.
+    fn session() -> Session {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+        Session::new(stream, Indexed::new(RingBuffer::new(None), |_| 0))
+    }
+
+    // Helper function to describe events so that they're easy to compare.
+    fn describe(events: Vec<ServerEvent>) -> Vec<String> {
+        events.into_iter().map(|event| match event {
+            ServerEvent::Text(s) => format!("text {:?}", s.as_str()),
+            ServerEvent::Prompt(s) => format!("prompt {:?}", s.as_str()),
+            ServerEvent::Gmcp(package, _) => format!("gmcp {}", package),
+            ServerEvent::Msdp(variable, _) => format!("msdp {}", variable),
+            ServerEvent::Sound(trigger) => format!("sound {}", trigger.file)
+        }).collect()
+    }
+
+    // Helper function to feed a read to a session and describe the events.
+    fn feed(session: &mut Session, data: &[u8]) -> Vec<String> {
+        describe(handle_socket_data(data, session))
+    }
+
+    // Helper function to make a partial line look like it has been waiting
+    // longer than the timeout.
+    fn expire(session: &mut Session) {
+        let waited = Duration::from_millis(PARTIAL_LINE_TIMEOUT_MS + 1);
+        session.partial_line_time = Some(Instant::now() - waited);
+    }
+
+    #[test]
+    fn line_split_across_reads() {
+        let mut session = session();
+        assert!(feed(&mut session, b"You see a gob").is_empty());
+        assert_eq!(session.partial_line.as_str(), "You see a gob");
+        assert!(session.partial_line_time.is_some());
+
+        assert_eq!(feed(&mut session, b"lin.\nIt snar"),
+            vec!["text \"You see a goblin.\\n\""]);
+        assert_eq!(session.partial_line.as_str(), "It snar");
+        assert_eq!(feed(&mut session, b"ls.\n"), vec!["text \"It snarls.\\n\""]);
+        assert!(session.partial_line.is_empty());
+        assert!(session.partial_line_time.is_none());
+    }
+
+    #[test]
+    fn multibyte_character_split_across_reads() {
+        let mut session = session();
+        let text = "caf\u{e9}\n".as_bytes();
+        assert!(feed(&mut session, &text[..4]).is_empty());
+        assert_eq!(feed(&mut session, &text[4..]), vec!["text \"caf\u{e9}\\n\""]);
+    }
+
+    #[test]
+    fn line_ending_split_across_reads() {
+        let mut session = session();
+        assert!(feed(&mut session, b"one\r").is_empty());
+        assert_eq!(feed(&mut session, b"\ntwo\r\n"),
+            vec!["text \"one\\r\\ntwo\\r\\n\""]);
+        assert!(session.partial_line.is_empty());
+    }
+
+    #[test]
+    fn prompt_marked_after_partial_line() {
+        let mut session = session();
+        assert_eq!(feed(&mut session, b"Welcome!\nName"),
+            vec!["text \"Welcome!\\n\""]);
+        assert_eq!(session.partial_line.as_str(), "Name");
+        assert_eq!(feed(&mut session, &[b'?', b' ', telnet::IAC, telnet::GA]),
+            vec!["prompt \"Name? \""]);
+        assert!(session.partial_line.is_empty());
+        assert!(session.partial_line_time.is_none());
+
+        // The same with EOR, with the marker arriving on its own.
+        assert_eq!(feed(&mut session, b"Done.\r\nHP: 10> "),
+            vec!["text \"Done.\\r\\n\""]);
+        assert_eq!(feed(&mut session, &[telnet::IAC, telnet::EOR]),
+            vec!["prompt \"HP: 10> \""]);
     }
-    if let Some(b) = bg_color {
-        session.char_format.bg_color = b;
+
+    #[test]
+    fn unmarked_prompt_is_flushed_after_timeout() {
+        let mut session = session();
+        feed(&mut session, b"Password: ");
+        assert!(describe(take_timed_out_line(&mut session)).is_empty());
+
+        expire(&mut session);
+        assert_eq!(describe(take_timed_out_line(&mut session)),
+            vec!["prompt \"Password: \""]);
+        assert!(session.partial_line.is_empty());
+        assert!(session.partial_line_time.is_none());
+        assert!(describe(take_timed_out_line(&mut session)).is_empty());
+    }
+
+    #[test]
+    fn timeout_only_restarts_when_the_partial_line_changes() {
+        let mut session = session();
+        feed(&mut session, b"Password: ");
+        expire(&mut session);
+
+        // Reads that don't add to the line leave the timeout alone.
+        feed(&mut session, &gmcp::message("Char.Vitals", "{ \"hp\": 10 }"));
+        feed(&mut session, &[telnet::IAC, telnet::NOP]);
+        let expired = session.partial_line_time.unwrap();
+        assert!(expired.elapsed() >= Duration::from_millis(PARTIAL_LINE_TIMEOUT_MS));
+
+        // More text starts it over.
+        feed(&mut session, b"*");
+        assert!(session.partial_line_time.unwrap() > expired);
+    }
+
+    #[test]
+    fn mxp_links_are_kept_on_the_session() {
+        let mut session = session();
+        feed(&mut session, &[telnet::IAC, telnet::WILL, telnet::MXP]);
+        assert!(session.options.is_remote_enabled(telnet::MXP));
+        let events = handle_socket_data(
+            b"\x1B[1z<send href=\"go &text;\" hint=\"Go\">north</send>\n", &mut session);
+        let line = match events.into_iter().next() {
+            Some(ServerEvent::Text(line)) => line,
+            _ => panic!("expected a line")
+        };
+        assert_eq!(line.as_str(), "north\n");
+        let links = line_links(&session, &line);
+        assert_eq!(links.len(), 1);
+        assert_eq!(links[0].0, "north");
+        assert_eq!(links[0].1.href, "go north");
+        assert_eq!(links[0].1.hint, Some("Go".to_string()));
+    }
+
+    #[test]
+    fn gag_from_hook_leaves_other_output_alone() {
+        let mut context = context(TestScripts {
+            recv_actions: vec![ScriptAction::GagLine,
+                ScriptAction::SendInput("x".to_string())],
+            trigger_actions: vec![]
+        });
+        let line = formatted_string::with_format("spam\n", Format::default());
+        receive_text(&mut context, &line, false);
+        let lines: Vec<String> = scrollback(&context).iter()
+            .map(|l| l.as_str().to_string()).collect();
+        assert_eq!(lines, vec!["x"]);
+    }
+
+    #[test]
+    fn trigger_edits_apply_to_the_received_line_only() {
+        let red = Format::with_fg(Color::Red);
+        let pattern = LinePattern(Regex::new("goblin").unwrap());
+        let mut context = context(TestScripts {
+            recv_actions: vec![],
+            trigger_actions: vec![ScriptAction::HighlightLine(pattern, red),
+                ScriptAction::SendInput("kill goblin".to_string())]
+        });
+        let line = formatted_string::with_format("A goblin.\n", Format::default());
+        receive_text(&mut context, &line, false);
+        let lines = scrollback(&context);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].as_str(), "A goblin.");
+        assert_eq!(lines[0].get(2).unwrap().1, red);
+        assert_eq!(lines[0].get(0).unwrap().1, Format::default());
+        assert_eq!(lines[1].as_str(), "kill goblin");
+        assert!(lines[1].iter().all(|(_, f)| f != red));
+    }
+
+    #[test]
+    fn search_highlight_follows_its_line() {
+        let mut context = context(TestScripts {
+            recv_actions: vec![],
+            trigger_actions: vec![]
+        });
+        write_scrollback(&mut context, formatted_string::with_format(
+            "a goblin\nan orc\nanother goblin\n", Format::default()));
+        search_backwards(&mut context, "goblin");
+
+        // New lines arrive before searching again, which finds the older
+        // goblin and clears the highlight from the newer one.
+        write_scrollback(&mut context,
+            formatted_string::with_format("a troll\n", Format::default()));
+        search_backwards(&mut context, "goblin");
+        let reversed: Vec<Vec<usize>> = scrollback(&context).iter()
+            .map(|line| line.iter().enumerate()
+                .filter(|&(_, (_, f))| f.style.reverse)
+                .map(|(i, _)| i).collect())
+            .collect();
+        assert_eq!(reversed, vec![vec![2, 3, 4, 5, 6, 7], vec![], vec![], vec![]]);
+    }
+
+    #[test]
+    fn sound_triggers_need_msp() {
+        let mut session = session();
+        assert_eq!(feed(&mut session, b"Bob tells you '!!SOUND(x.wav)'\n"),
+            vec!["text \"Bob tells you '!!SOUND(x.wav)'\\n\""]);
+
+        feed(&mut session, &[telnet::IAC, telnet::WILL, telnet::MSP]);
+        assert!(session.options.is_remote_enabled(telnet::MSP));
+        assert_eq!(feed(&mut session, b"Boom!!!SOUND(x.wav)\n"),
+            vec!["text \"Boom!\\n\"", "sound x.wav"]);
+    }
+
+    #[test]
+    fn events_keep_their_place_after_partial_lines() {
+        let mut session = session();
+        feed(&mut session, &[telnet::IAC, telnet::WILL, telnet::MSP]);
+        let mut data = b"first\nsec".to_vec();
+        data.extend(gmcp::message("Room.Info", "{}"));
+        data.extend(b"ond\nthird\n".iter());
+        data.extend(gmcp::message("Char.Vitals", "{}"));
+        assert_eq!(feed(&mut session, &data), vec![
+            "text \"first\\n\"",
+            "text \"second\\n\"",
+            "gmcp Room.Info",
+            "text \"third\\n\"",
+            "gmcp Char.Vitals"]);
+
+        // Events wait across reads for the line to be finished.
+        assert!(feed(&mut session, b"You hear ").is_empty());
+        let mut data = b"rain!!SOUND(rain.wav)".to_vec();
+        data.extend(gmcp::message("Room.Weather", "\"rain\""));
+        assert!(feed(&mut session, &data).is_empty());
+        assert_eq!(feed(&mut session, b".\nA"), vec![
+            "text \"You hear rain.\\n\"",
+            "sound rain.wav",
+            "gmcp Room.Weather"]);
+
+        // A prompt releases them too, whether it's marked or timed out.
+        feed(&mut session, &gmcp::message("Char.Vitals", "{}"));
+        let mut data = b">".to_vec();
+        data.extend([telnet::IAC, telnet::GA].iter());
+        assert_eq!(feed(&mut session, &data),
+            vec!["prompt \"A>\"", "gmcp Char.Vitals"]);
+
+        feed(&mut session, b"B");
+        feed(&mut session, &gmcp::message("Char.Vitals", "{}"));
+        expire(&mut session);
+        assert_eq!(describe(take_timed_out_line(&mut session)),
+            vec!["prompt \"B\"", "gmcp Char.Vitals"]);
     }
 }
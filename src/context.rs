@@ -1,6 +1,6 @@
 use actions;
 use indexed::Indexed;
-use scripting::{self, ScriptInterface};
+use scripting::{self, KeyBinding, ScriptInterface};
 use session::Session;
 use std::char;
 use std::collections::HashMap;
@@ -73,6 +73,43 @@ impl Context {
     {
         self.bindings.insert(keycode, Rc::new(Box::new(func)));
     }
+    // Replaces the bindings with the defaults plus those made by scripts,
+    // which take precedence. Returns an error for each script binding that
+    // couldn't be made.
+    pub fn rebuild_bindings(&mut self) -> Vec<String> {
+        self.bindings.clear();
+        self.set_default_bindings();
+
+        let mut errors = Vec::new();
+        for (key_name, binding) in self.script_interface.key_bindings() {
+            let code = match self.key_names_to_codes.get(&key_name) {
+                Some(c) => c.clone(),
+                None => {
+                    errors.push(format!("Unknown key: {}", key_name));
+                    continue;
+                }
+            };
+            match binding {
+                KeyBinding::Action(name) => match actions::get_builtin(&name) {
+                    Some(action) => self.bind_keycode(code, action),
+                    None => errors.push(format!("Unknown action for key {}: {}",
+                        key_name, name))
+                },
+                KeyBinding::Script => {
+                    self.bind_keycode(code, move |context: &mut Context| {
+                        let result = context.script_interface
+                            .run_key_binding(&key_name);
+                        actions::do_script_actions(context, result);
+                        true
+                    });
+                },
+                KeyBinding::Unbound => {
+                    self.bindings.remove(&code);
+                }
+            }
+        }
+        errors
+    }
     fn set_default_bindings(&mut self) {
         self.bind_key("F12", actions::quit);
         self.bind_key("PAGEUP", actions::prev_page);
@@ -0,0 +1,170 @@
+// Character encodings that tome can use for text from the server.
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
+pub enum Encoding {
+    Utf8,
+    Latin1,
+    Cp437
+}
+
+impl Encoding {
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        match &name.to_uppercase()[..] {
+            "UTF-8" | "UTF8" => Some(Encoding::Utf8),
+            "ISO-8859-1" | "ISO_8859-1" | "LATIN-1" | "LATIN1" =>
+                Some(Encoding::Latin1),
+            "IBM437" | "CP437" => Some(Encoding::Cp437),
+            _ => None
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Latin1 => "ISO-8859-1",
+            Encoding::Cp437 => "IBM437"
+        }
+    }
+}
+
+// Incrementally decodes text from the server, one byte at a time, so that
+// multi-byte characters can be split across reads. Bytes that aren't valid
+// in the main encoding are decoded with the fallback encoding instead.
+#[derive(Debug)]
+pub struct Decoder {
+    encoding: Encoding,
+    fallback: Encoding,
+    pending: Vec<u8>,
+    remaining: usize
+}
+
+impl Decoder {
+    pub fn new(encoding: Encoding, fallback: Encoding) -> Decoder {
+        Decoder {
+            encoding: encoding,
+            fallback: fallback,
+            pending: Vec::new(),
+            remaining: 0
+        }
+    }
+    pub fn encoding(&self) -> Encoding { self.encoding }
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+        self.pending.clear();
+        self.remaining = 0;
+    }
+    pub fn set_fallback(&mut self, fallback: Encoding) {
+        self.fallback = fallback;
+    }
+
+    // Decodes the next byte, adding any completed characters to chars.
+    pub fn decode(&mut self, byte: u8, chars: &mut Vec<char>) {
+        match self.encoding {
+            Encoding::Utf8 => self.decode_utf8(byte, chars),
+            e => chars.push(decode_byte(byte, e))
+        }
+    }
+
+    // Helper function to decode a byte that is part of a UTF-8 stream.
+    fn decode_utf8(&mut self, byte: u8, chars: &mut Vec<char>) {
+        if self.remaining > 0 {
+            if byte & 0xC0 == 0x80 {
+                // Continuation byte.
+                self.pending.push(byte);
+                self.remaining -= 1;
+                if self.remaining == 0 {
+                    match ::std::str::from_utf8(&self.pending) {
+                        Ok(s) => chars.extend(s.chars()),
+                        Err(_) => {
+                            // Overlong or otherwise bad sequence.
+                            for b in self.pending.iter() {
+                                chars.push(decode_byte(*b, self.fallback));
+                            }
+                        }
+                    }
+                    self.pending.clear();
+                }
+                return;
+            }
+
+            // The sequence was cut short; fall back for what was received.
+            for b in self.pending.iter() {
+                chars.push(decode_byte(*b, self.fallback));
+            }
+            self.pending.clear();
+            self.remaining = 0;
+        }
+
+        let expected = match byte {
+            0x00...0x7F => {
+                chars.push(byte as char);
+                return;
+            },
+            0xC2...0xDF => 1,
+            0xE0...0xEF => 2,
+            0xF0...0xF4 => 3,
+            _ => {
+                chars.push(decode_byte(byte, self.fallback));
+                return;
+            }
+        };
+        self.pending.push(byte);
+        self.remaining = expected;
+    }
+}
+
+// Encodes text to be sent to the server. Characters that can't be
+// represented are replaced with '?'.
+pub fn encode(s: &str, encoding: Encoding) -> Vec<u8> {
+    match encoding {
+        Encoding::Utf8 => s.as_bytes().to_vec(),
+        Encoding::Latin1 => s.chars()
+            .map(|c| if (c as u32) < 0x100 {c as u8} else {b'?'})
+            .collect(),
+        Encoding::Cp437 => s.chars()
+            .map(|c| {
+                if (c as u32) < 0x80 {
+                    c as u8
+                } else {
+                    match CP437_HIGH.iter().position(|h| *h == c) {
+                        Some(i) => (i + 0x80) as u8,
+                        None => b'?'
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+// Helper function to decode a byte using a single-byte encoding.
+fn decode_byte(byte: u8, encoding: Encoding) -> char {
+    match encoding {
+        Encoding::Cp437 if byte >= 0x80 => CP437_HIGH[(byte - 0x80) as usize],
+        Encoding::Utf8 if byte >= 0x80 => '\u{FFFD}',
+        _ => byte as char
+    }
+}
+
+// Characters for the upper half of code page 437.
+const CP437_HIGH: [char; 128] = [
+    '\u{00C7}', '\u{00FC}', '\u{00E9}', '\u{00E2}', '\u{00E4}', '\u{00E0}',
+    '\u{00E5}', '\u{00E7}', '\u{00EA}', '\u{00EB}', '\u{00E8}', '\u{00EF}',
+    '\u{00EE}', '\u{00EC}', '\u{00C4}', '\u{00C5}', '\u{00C9}', '\u{00E6}',
+    '\u{00C6}', '\u{00F4}', '\u{00F6}', '\u{00F2}', '\u{00FB}', '\u{00F9}',
+    '\u{00FF}', '\u{00D6}', '\u{00DC}', '\u{00A2}', '\u{00A3}', '\u{00A5}',
+    '\u{20A7}', '\u{0192}', '\u{00E1}', '\u{00ED}', '\u{00F3}', '\u{00FA}',
+    '\u{00F1}', '\u{00D1}', '\u{00AA}', '\u{00BA}', '\u{00BF}', '\u{2310}',
+    '\u{00AC}', '\u{00BD}', '\u{00BC}', '\u{00A1}', '\u{00AB}', '\u{00BB}',
+    '\u{2591}', '\u{2592}', '\u{2593}', '\u{2502}', '\u{2524}', '\u{2561}',
+    '\u{2562}', '\u{2556}', '\u{2555}', '\u{2563}', '\u{2551}', '\u{2557}',
+    '\u{255D}', '\u{255C}', '\u{255B}', '\u{2510}', '\u{2514}', '\u{2534}',
+    '\u{252C}', '\u{251C}', '\u{2500}', '\u{253C}', '\u{255E}', '\u{255F}',
+    '\u{255A}', '\u{2554}', '\u{2569}', '\u{2566}', '\u{2560}', '\u{2550}',
+    '\u{256C}', '\u{2567}', '\u{2568}', '\u{2564}', '\u{2565}', '\u{2559}',
+    '\u{2558}', '\u{2552}', '\u{2553}', '\u{256B}', '\u{256A}', '\u{2518}',
+    '\u{250C}', '\u{2588}', '\u{2584}', '\u{258C}', '\u{2590}', '\u{2580}',
+    '\u{03B1}', '\u{00DF}', '\u{0393}', '\u{03C0}', '\u{03A3}', '\u{03C3}',
+    '\u{00B5}', '\u{03C4}', '\u{03A6}', '\u{0398}', '\u{03A9}', '\u{03B4}',
+    '\u{221E}', '\u{03C6}', '\u{03B5}', '\u{2229}', '\u{2261}', '\u{00B1}',
+    '\u{2265}', '\u{2264}', '\u{2320}', '\u{2321}', '\u{00F7}', '\u{2248}',
+    '\u{00B0}', '\u{2219}', '\u{00B7}', '\u{221A}', '\u{207F}', '\u{00B2}',
+    '\u{25A0}', '\u{00A0}'
+];
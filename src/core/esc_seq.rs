@@ -1,11 +1,28 @@
-use formatted_string::{Style, Color};
+use formatted_string::{Color, Format};
 use parse_state::ParseState;
 use regex::Regex;
 
 const SEQ_BEGIN: u8 = 0x1B;
-const SEQ_END: u8 = 0x6D;
-const SEQ_MAX_SIZE: usize = 15;
+const CSI_BEGIN: u8 = 0x5B; // '['
+const OSC_BEGIN: u8 = 0x5D; // ']'
+const DCS_BEGIN: u8 = 0x50; // 'P'
+const SOS_BEGIN: u8 = 0x58; // 'X'
+const PM_BEGIN: u8 = 0x5E; // '^'
+const APC_BEGIN: u8 = 0x5F; // '_'
+const ST_END: u8 = 0x5C; // '\', following ESC to form the string terminator.
+const BEL: u8 = 0x07;
+const CAN: u8 = 0x18;
+const SUB: u8 = 0x1A;
+const SGR_END: u8 = 0x6D; // 'm'
 
+// Longest control sequence that will be accepted. Control strings (e.g. OSC
+// window titles) can be much longer.
+const SEQ_MAX_SIZE: usize = 64;
+const STRING_MAX_SIZE: usize = 4096;
+
+// Parses escape sequences as described in ECMA-48: control sequences
+// (ESC [ ...), control strings (ESC ] ... ST and the like), and escape
+// sequences with optional intermediate bytes (ESC (B, ESC 7, etc.).
 pub fn parse(old_state: &ParseState, byte: u8) -> ParseState {
     match *old_state {
         ParseState::NotInProgress => {
@@ -16,29 +33,100 @@ pub fn parse(old_state: &ParseState, byte: u8) -> ParseState {
             let mut bytes = b.clone();
             bytes.push(byte);
 
-            // Check if the command has exceeded the max size.
-            if bytes.len() > SEQ_MAX_SIZE {
-                return ParseState::Error(bytes);
+            if bytes.len() == 2 {
+                return match byte {
+                    CSI_BEGIN | OSC_BEGIN | DCS_BEGIN | SOS_BEGIN | PM_BEGIN |
+                        APC_BEGIN => ParseState::InProgress(bytes),
+                    0x20...0x2F => ParseState::InProgress(bytes), // Intermediate.
+                    0x30...0x7E => ParseState::Success(bytes),
+                    SEQ_BEGIN => ParseState::InProgress(vec![byte]),
+                    _ => ParseState::Error(bytes)
+                };
             }
 
-            if byte == SEQ_END {
-                return ParseState::Success(bytes);
+            match bytes[1] {
+                CSI_BEGIN => parse_control_sequence(bytes, byte),
+                OSC_BEGIN | DCS_BEGIN | SOS_BEGIN | PM_BEGIN | APC_BEGIN =>
+                    parse_control_string(bytes, byte),
+                _ => match byte {
+                    0x20...0x2F => ParseState::InProgress(bytes),
+                    0x30...0x7E => ParseState::Success(bytes),
+                    SEQ_BEGIN => ParseState::InProgress(vec![byte]),
+                    _ => ParseState::Error(bytes)
+                }
             }
-
-            ParseState::InProgress(bytes)
         },
         ParseState::Success(_) => parse(&ParseState::NotInProgress, byte),
         ParseState::Error(_) => parse(&ParseState::NotInProgress, byte)
     }
 }
 
-pub fn interpret(esc_seq: &[u8]) -> (Option<Style>, Option<Color>, Option<Color>) {
+// Helper function to continue parsing a control sequence. These are made up
+// of parameter bytes, then intermediate bytes, then a single final byte.
+fn parse_control_sequence(bytes: Vec<u8>, byte: u8) -> ParseState {
+    // Check if the sequence has exceeded the max size.
+    if bytes.len() > SEQ_MAX_SIZE {
+        return ParseState::Error(bytes);
+    }
+
+    let after_intermediate = match bytes[bytes.len() - 2] {
+        0x20...0x2F => true,
+        _ => false
+    };
+    match byte {
+        0x30...0x3F if !after_intermediate => ParseState::InProgress(bytes),
+        0x20...0x2F => ParseState::InProgress(bytes),
+        0x40...0x7E => ParseState::Success(bytes),
+        // A new sequence interrupts this one.
+        SEQ_BEGIN => ParseState::InProgress(vec![byte]),
+        _ => ParseState::Error(bytes)
+    }
+}
+
+// Helper function to continue parsing a control string. These end with the
+// string terminator (ESC \), or BEL as used by most terminals for OSC.
+fn parse_control_string(bytes: Vec<u8>, byte: u8) -> ParseState {
+    if bytes.len() > STRING_MAX_SIZE {
+        return ParseState::Error(bytes);
+    }
+
+    let after_esc = bytes[bytes.len() - 2] == SEQ_BEGIN;
+    match byte {
+        ST_END if after_esc => ParseState::Success(bytes),
+        _ if after_esc => ParseState::Error(bytes),
+        BEL => ParseState::Success(bytes),
+        CAN | SUB => ParseState::Error(bytes),
+        _ => ParseState::InProgress(bytes)
+    }
+}
+
+// Checks whether a byte that ended a sequence early should still take effect
+// as text. Control characters like newlines interrupt a sequence but aren't
+// part of it.
+pub fn interrupted_by_control(bad_seq: &[u8]) -> bool {
+    match bad_seq.last() {
+        Some(&b) if bad_seq.len() > 1 => b < 0x20 && b != CAN && b != SUB &&
+            b != SEQ_BEGIN,
+        _ => false
+    }
+}
+
+// Checks whether a sequence selects graphic rendition (colors and styles).
+// Other sequences (cursor movement, erasing, window titles, etc.) have no
+// meaning for a scrollback buffer.
+pub fn is_sgr(seq: &[u8]) -> bool {
+    seq.len() >= 3 && seq[1] == CSI_BEGIN && seq[seq.len() - 1] == SGR_END
+}
+
+// Applies a select graphic rendition sequence (e.g. ESC[1;31m) to a format.
+// Other sequences leave the format as it is.
+pub fn interpret(esc_seq: &[u8], format: Format) -> Format {
     let seq_str = match ::std::str::from_utf8(esc_seq) {
         Ok(s) => s,
-        Err(_) => return (None, None, None)
+        Err(_) => return format
     };
     let full_regex =
-        Regex::new(r"^\x{1B}\[([0-9;]*)m$").unwrap();
+        Regex::new(r"^\x{1B}\[([0-9;:]*)m$").unwrap();
     let inside = match full_regex.captures(seq_str) {
         Some(caps) => caps.at(1),
         None => None
@@ -46,49 +134,165 @@ pub fn interpret(esc_seq: &[u8]) -> (Option<Style>, Option<Color>, Option<Color>
     let mut parts = Vec::new();
     match inside {
         // Special case for ESC[m.
-        Some("") => return
-            (Some(Style::Normal), Some(Color::Default), Some(Color::Default)),
+        Some("") => return reset(format),
         Some(ins) => {
             for part in ins.split(";") {
                 if part.len() > 0 { parts.push(part); }
             }
         }
-        None => return (None, None, None)
+        None => return format
     }
 
-    // Extract the attributes from the escape sequence.
-    let mut style = None;
-    let mut fg_color = None;
-    let mut bg_color = None;
-    for part in parts {
-        match part {
-            "0" => {
-                style = Some(Style::Normal);
-                fg_color = Some(Color::Default);
-                bg_color = Some(Color::Default);
+    // Apply the attributes from the escape sequence in order.
+    let mut format = format;
+    let mut i = 0;
+    while i < parts.len() {
+        match parts[i] {
+            "0" => format = reset(format),
+            "1" => format.style.bold = true,
+            "2" => format.style.dim = true,
+            "3" => format.style.italic = true,
+            "4" | "21" => format.style.underline = true,
+            "5" | "6" => format.style.blink = true,
+            "7" => format.style.reverse = true,
+            "9" => format.style.strikethrough = true,
+            "22" => {
+                format.style.bold = false;
+                format.style.dim = false;
+            },
+            "23" => format.style.italic = false,
+            "24" => format.style.underline = false,
+            "25" => format.style.blink = false,
+            "27" => format.style.reverse = false,
+            "29" => format.style.strikethrough = false,
+            "30" => format.fg_color = Color::Black,
+            "31" => format.fg_color = Color::Red,
+            "32" => format.fg_color = Color::Green,
+            "33" => format.fg_color = Color::Yellow,
+            "34" => format.fg_color = Color::Blue,
+            "35" => format.fg_color = Color::Magenta,
+            "36" => format.fg_color = Color::Cyan,
+            "37" => format.fg_color = Color::White,
+            "39" => format.fg_color = Color::Default,
+            "40" => format.bg_color = Color::Black,
+            "41" => format.bg_color = Color::Red,
+            "42" => format.bg_color = Color::Green,
+            "43" => format.bg_color = Color::Yellow,
+            "44" => format.bg_color = Color::Blue,
+            "45" => format.bg_color = Color::Magenta,
+            "46" => format.bg_color = Color::Cyan,
+            "47" => format.bg_color = Color::White,
+            "49" => format.bg_color = Color::Default,
+            "90" => format.fg_color = Color::BrightBlack,
+            "91" => format.fg_color = Color::BrightRed,
+            "92" => format.fg_color = Color::BrightGreen,
+            "93" => format.fg_color = Color::BrightYellow,
+            "94" => format.fg_color = Color::BrightBlue,
+            "95" => format.fg_color = Color::BrightMagenta,
+            "96" => format.fg_color = Color::BrightCyan,
+            "97" => format.fg_color = Color::BrightWhite,
+            "100" => format.bg_color = Color::BrightBlack,
+            "101" => format.bg_color = Color::BrightRed,
+            "102" => format.bg_color = Color::BrightGreen,
+            "103" => format.bg_color = Color::BrightYellow,
+            "104" => format.bg_color = Color::BrightBlue,
+            "105" => format.bg_color = Color::BrightMagenta,
+            "106" => format.bg_color = Color::BrightCyan,
+            "107" => format.bg_color = Color::BrightWhite,
+            "38" | "48" => {
+                // 256 colors (38;5;n) or RGB (38;2;r;g;b).
+                let (color, used) = extended_color(&parts[i + 1..], false);
+                set_color(&mut format, parts[i] == "38", color);
+                i += used;
+            },
+            p if p.starts_with("38:") || p.starts_with("48:") => {
+                // The same, with the parameters separated by colons instead.
+                let sub_parts: Vec<&str> = p.split(":").collect();
+                let (color, _) = extended_color(&sub_parts[1..], true);
+                set_color(&mut format, p.starts_with("38"), color);
             },
-            "1" => style = Some(Style::Bold),
-            "30" => fg_color = Some(Color::Black),
-            "31" => fg_color = Some(Color::Red),
-            "32" => fg_color = Some(Color::Green),
-            "33" => fg_color = Some(Color::Yellow),
-            "34" => fg_color = Some(Color::Blue),
-            "35" => fg_color = Some(Color::Magenta),
-            "36" => fg_color = Some(Color::Cyan),
-            "37" => fg_color = Some(Color::White),
-            "39" => fg_color = Some(Color::Default),
-            "40" => bg_color = Some(Color::Black),
-            "41" => bg_color = Some(Color::Red),
-            "42" => bg_color = Some(Color::Green),
-            "43" => bg_color = Some(Color::Yellow),
-            "44" => bg_color = Some(Color::Blue),
-            "45" => bg_color = Some(Color::Magenta),
-            "46" => bg_color = Some(Color::Cyan),
-            "47" => bg_color = Some(Color::White),
-            "49" => bg_color = Some(Color::Default),
             _ => () // Ignore unknown parts.
         }
+        i += 1;
     }
 
-    return (style, fg_color, bg_color);
+    return format;
+}
+
+// Helper function to go back to the default colors and style. Links aren't
+// affected by escape sequences.
+fn reset(format: Format) -> Format {
+    Format { link: format.link, ..Format::default() }
+}
+
+// Helper function to set the fg or bg color, if a valid color was given.
+fn set_color(format: &mut Format, fg: bool, color: Option<Color>) {
+    match color {
+        Some(c) if fg => format.fg_color = c,
+        Some(c) => format.bg_color = c,
+        None => ()
+    }
+}
+
+// Helper function to read the color from the parameters following 38 or 48.
+// Returns the color (if valid) and the number of parameters used. In the
+// colon form, the parts are only those of the one parameter.
+fn extended_color(parts: &[&str], colon: bool) -> (Option<Color>, usize) {
+    let number = |i: usize| parts.get(i).and_then(|p| p.parse::<u8>().ok());
+    match parts.get(0) {
+        Some(&"5") => (number(1).map(Color::Indexed), 2),
+        Some(&"2") => {
+            // The colon form can include a color space ID (which may be
+            // empty) before the RGB values. It's ignored.
+            let offset = if colon && parts.len() == 5 {2} else {1};
+            match (number(offset), number(offset + 1), number(offset + 2)) {
+                (Some(r), Some(g), Some(b)) => (Some(Color::Rgb(r, g, b)), offset + 3),
+                _ => (None, offset + 3)
+            }
+        },
+        _ => (None, 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use formatted_string::{Color, Format};
+    use super::*;
+
+    // Helper function to apply an SGR sequence to the default format.
+    fn sgr(params: &str) -> Format {
+        interpret(format!("\x1B[{}m", params).as_bytes(), Format::default())
+    }
+
+    #[test]
+    fn semicolon_extended_colors() {
+        assert_eq!(sgr("38;5;196").fg_color, Color::Indexed(196));
+        assert_eq!(sgr("48;2;255;128;0").bg_color, Color::Rgb(255, 128, 0));
+
+        // Parameters after the color are still read.
+        let format = sgr("38;2;1;2;3;1");
+        assert_eq!(format.fg_color, Color::Rgb(1, 2, 3));
+        assert!(format.style.bold);
+    }
+
+    #[test]
+    fn colon_extended_colors() {
+        assert_eq!(sgr("38:5:196").fg_color, Color::Indexed(196));
+        assert_eq!(sgr("38:2:255:128:0").fg_color, Color::Rgb(255, 128, 0));
+        assert_eq!(sgr("48:2:255:128:0;1").bg_color, Color::Rgb(255, 128, 0));
+    }
+
+    #[test]
+    fn colon_colors_skip_the_color_space_id() {
+        assert_eq!(sgr("38:2::255:128:0").fg_color, Color::Rgb(255, 128, 0));
+        assert_eq!(sgr("38:2:0:255:128:0").fg_color, Color::Rgb(255, 128, 0));
+        assert_eq!(sgr("48:2:1:10:20:30").bg_color, Color::Rgb(10, 20, 30));
+    }
+
+    #[test]
+    fn bad_extended_colors_are_ignored() {
+        assert_eq!(sgr("38:2:255:128").fg_color, Color::Default);
+        assert_eq!(sgr("38;5;256").fg_color, Color::Default);
+        assert_eq!(sgr("38:2:300:0:0").fg_color, Color::Default);
+    }
 }
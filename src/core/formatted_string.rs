@@ -1,8 +1,47 @@
+use regex::Regex;
+use std::cmp;
+use std::iter::FromIterator;
+use std::mem;
+use std::slice;
+use std::str::Chars;
+use std::vec;
+
+// Text attributes, any combination of which can be used at once.
 #[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
-pub enum Style {
-    Normal,
-    Bold,
-    Standout
+pub struct Style {
+    pub bold: bool,
+    pub dim: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub blink: bool,
+    pub reverse: bool,
+    pub strikethrough: bool
+}
+
+impl Style {
+    pub fn normal() -> Style {
+        Style {
+            bold: false,
+            dim: false,
+            italic: false,
+            underline: false,
+            blink: false,
+            reverse: false,
+            strikethrough: false
+        }
+    }
+    // Combines two styles, keeping every attribute that is set in either.
+    pub fn union(&self, other: Style) -> Style {
+        Style {
+            bold: self.bold || other.bold,
+            dim: self.dim || other.dim,
+            italic: self.italic || other.italic,
+            underline: self.underline || other.underline,
+            blink: self.blink || other.blink,
+            reverse: self.reverse || other.reverse,
+            strikethrough: self.strikethrough || other.strikethrough
+        }
+    }
 }
 
 #[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
@@ -15,37 +54,415 @@ pub enum Color {
     Blue,
     Magenta,
     Cyan,
-    White
+    White,
+    BrightBlack,
+    BrightRed,
+    BrightGreen,
+    BrightYellow,
+    BrightBlue,
+    BrightMagenta,
+    BrightCyan,
+    BrightWhite,
+    // A color from the 256 color palette.
+    Indexed(u8),
+    Rgb(u8, u8, u8)
+}
+
+// Levels used by each component of the 6x6x6 color cube in the 256 color
+// palette.
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+// The usual (xterm) values of the first 16 colors.
+const BASE_PALETTE: [(u8, u8, u8); 16] = [
+    (0, 0, 0), (205, 0, 0), (0, 205, 0), (205, 205, 0),
+    (0, 0, 238), (205, 0, 205), (0, 205, 205), (229, 229, 229),
+    (127, 127, 127), (255, 0, 0), (0, 255, 0), (255, 255, 0),
+    (92, 92, 255), (255, 0, 255), (0, 255, 255), (255, 255, 255)
+];
+
+impl Color {
+    // Gets the index of the color in the 256 color palette, or None for the
+    // default color.
+    pub fn palette_index(&self) -> Option<u8> {
+        match *self {
+            Color::Default => None,
+            Color::Black => Some(0),
+            Color::Red => Some(1),
+            Color::Green => Some(2),
+            Color::Yellow => Some(3),
+            Color::Blue => Some(4),
+            Color::Magenta => Some(5),
+            Color::Cyan => Some(6),
+            Color::White => Some(7),
+            Color::BrightBlack => Some(8),
+            Color::BrightRed => Some(9),
+            Color::BrightGreen => Some(10),
+            Color::BrightYellow => Some(11),
+            Color::BrightBlue => Some(12),
+            Color::BrightMagenta => Some(13),
+            Color::BrightCyan => Some(14),
+            Color::BrightWhite => Some(15),
+            Color::Indexed(n) => Some(n),
+            Color::Rgb(r, g, b) => Some(nearest_cube_color(r, g, b))
+        }
+    }
+
+    // Gets the index of the closest color for a terminal that only supports
+    // the given number of colors (8, 16, or 256), or None for the default
+    // color.
+    pub fn nearest_index(&self, num_colors: u32) -> Option<u8> {
+        let index = match self.palette_index() {
+            Some(i) => i,
+            None => return None
+        };
+        if num_colors >= 256 || (index as u32) < num_colors {
+            return Some(index);
+        }
+        let limit = if num_colors >= 16 {16} else {8};
+        let (r, g, b) = match *self {
+            Color::Rgb(r, g, b) => (r, g, b),
+            _ => palette_rgb(index)
+        };
+        Some(nearest_base_color(r, g, b, limit))
+    }
+}
+
+// Helper function to get the usual RGB value of a color in the 256 color
+// palette.
+fn palette_rgb(index: u8) -> (u8, u8, u8) {
+    match index {
+        0...15 => BASE_PALETTE[index as usize],
+        16...231 => {
+            let i = (index - 16) as usize;
+            (CUBE_LEVELS[i / 36], CUBE_LEVELS[(i / 6) % 6], CUBE_LEVELS[i % 6])
+        },
+        _ => {
+            let level = 8 + 10 * (index - 232);
+            (level, level, level)
+        }
+    }
+}
+
+// Helper function to find the closest color in the color cube or grayscale
+// ramp of the 256 color palette.
+fn nearest_cube_color(r: u8, g: u8, b: u8) -> u8 {
+    let nearest_level = |c: u8| (0..6)
+        .min_by_key(|&i| (CUBE_LEVELS[i] as i32 - c as i32).abs())
+        .unwrap();
+    let (ri, gi, bi) = (nearest_level(r), nearest_level(g), nearest_level(b));
+    let cube_index = (16 + 36 * ri + 6 * gi + bi) as u8;
+
+    let average = (r as u32 + g as u32 + b as u32) / 3;
+    let gray_index = (232 + (cmp::min(average.saturating_sub(3), 230) / 10))
+        as u8;
+
+    if distance((r, g, b), palette_rgb(gray_index)) <
+        distance((r, g, b), palette_rgb(cube_index))
+    {
+        gray_index
+    } else {
+        cube_index
+    }
+}
+
+// Helper function to find the closest of the first few palette colors.
+fn nearest_base_color(r: u8, g: u8, b: u8, limit: usize) -> u8 {
+    (0..limit)
+        .min_by_key(|&i| distance((r, g, b), BASE_PALETTE[i]))
+        .unwrap() as u8
+}
+
+// Helper function to get the (squared) distance between two colors.
+fn distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> u32 {
+    let diff = |x: u8, y: u8| (x as i32 - y as i32) * (x as i32 - y as i32);
+    (diff(a.0, b.0) + diff(a.1, b.1) + diff(a.2, b.2)) as u32
 }
 
 #[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
 pub struct Format {
     pub style: Style,
     pub fg_color: Color,
-    pub bg_color: Color
+    pub bg_color: Color,
+    // Id of the MXP link this text belongs to, if any. The session keeps
+    // a table of links by id.
+    pub link: Option<usize>
 }
 
 impl Format {
     pub fn default() -> Format {
-        Format { style: Style::Normal, fg_color: Color::Default,
-            bg_color: Color::Default }
+        Format { style: Style::normal(), fg_color: Color::Default,
+            bg_color: Color::Default, link: None }
     }
     pub fn with_fg(color: Color) -> Format {
-        Format { style: Style::Normal, fg_color: color,
-            bg_color: Color::Default }
+        Format { style: Style::normal(), fg_color: color,
+            bg_color: Color::Default, link: None }
     }
 }
 
-pub type FormattedString = Vec<(char, Format)>;
+// Text along with its formatting. Rather than storing a format for every
+// character, the formatting is stored as runs of characters that share the
+// same format. Indices are character (not byte) indices.
+#[derive(Debug, Eq, PartialEq, Clone, Hash)]
+pub struct FormattedString {
+    text: String,
+    // Runs of (number of characters, format) covering the text in order.
+    // Neighbouring runs always have different formats, and none are empty.
+    spans: Vec<(usize, Format)>,
+    // The number of characters in the text.
+    len: usize
+}
+
+impl FormattedString {
+    pub fn new() -> FormattedString {
+        FormattedString { text: String::new(), spans: Vec::new(), len: 0 }
+    }
+    pub fn len(&self) -> usize { self.len }
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+    pub fn as_str(&self) -> &str { &self.text }
+    pub fn push(&mut self, c: (char, Format)) {
+        let (ch, format) = c;
+        self.text.push(ch);
+        self.add_span(1, format);
+    }
+    pub fn push_str(&mut self, s: &str, format: Format) {
+        self.text.push_str(s);
+        self.add_span(s.chars().count(), format);
+    }
+    // Moves all the text from other onto the end of this string.
+    pub fn append(&mut self, other: FormattedString) {
+        self.text.push_str(&other.text);
+        for (count, format) in other.spans.into_iter() {
+            self.add_span(count, format);
+        }
+    }
+    pub fn clear(&mut self) {
+        self.text.clear();
+        self.spans.clear();
+        self.len = 0;
+    }
+    pub fn get(&self, index: usize) -> Option<(char, Format)> {
+        self.iter().nth(index)
+    }
+    pub fn insert(&mut self, index: usize, c: (char, Format)) {
+        let after = self.split_off(index);
+        self.push(c);
+        self.append(after);
+    }
+    pub fn remove(&mut self, index: usize) -> (char, Format) {
+        let mut after = self.split_off(index);
+        let removed = after.get(0).expect("index out of bounds");
+        self.append(after.split_off(1));
+        removed
+    }
+    // Splits the string in two at the given index, returning everything from
+    // the index on.
+    pub fn split_off(&mut self, index: usize) -> FormattedString {
+        let after = self.slice(index, self.len);
+        self.truncate(index);
+        after
+    }
+    pub fn truncate(&mut self, index: usize) {
+        if index >= self.len {
+            return;
+        }
+        let byte_index = self.byte_index(index);
+        self.text.truncate(byte_index);
+        let mut remaining = index;
+        let mut num_spans = 0;
+        for &mut (ref mut count, _) in self.spans.iter_mut() {
+            if remaining == 0 {
+                break;
+            }
+            if *count > remaining {
+                *count = remaining;
+            }
+            remaining -= *count;
+            num_spans += 1;
+        }
+        self.spans.truncate(num_spans);
+        self.len = index;
+    }
+    // Gets the text between the start (inclusive) and end (exclusive)
+    // indices.
+    pub fn slice(&self, start: usize, end: usize) -> FormattedString {
+        let end = cmp::min(end, self.len);
+        let mut fs = FormattedString::new();
+        if start >= end {
+            return fs;
+        }
+        fs.text.push_str(&self.text[self.byte_index(start)..self.byte_index(end)]);
+        let mut span_start = 0;
+        for &(count, format) in self.spans.iter() {
+            let span_end = span_start + count;
+            let overlap_start = cmp::max(span_start, start);
+            let overlap_end = cmp::min(span_end, end);
+            if overlap_start < overlap_end {
+                fs.add_span(overlap_end - overlap_start, format);
+            }
+            span_start = span_end;
+        }
+        fs
+    }
+    // Changes the format of the text between the start (inclusive) and end
+    // (exclusive) indices.
+    pub fn map_format<F: Fn(Format) -> Format>(&mut self, start: usize,
+        end: usize, f: F)
+    {
+        let after = self.split_off(end);
+        let middle = self.split_off(start);
+        for (count, format) in middle.spans.into_iter() {
+            self.add_span(count, f(format));
+        }
+        self.text.push_str(&middle.text);
+        self.append(after);
+    }
+    // Finds the last occurrence of a character.
+    pub fn rfind(&self, ch: char) -> Option<usize> {
+        self.text.rfind(ch).map(|b| self.text[..b].chars().count())
+    }
+    // Finds the first match of a regex, returning its start (inclusive) and
+    // end (exclusive) indices.
+    pub fn find_regex(&self, regex: &Regex) -> Option<(usize, usize)> {
+        regex.find(&self.text).map(|(start, end)| {
+            let start_index = self.text[..start].chars().count();
+            (start_index, start_index + self.text[start..end].chars().count())
+        })
+    }
+    // Finds every non-overlapping match of a regex, as character indices.
+    pub fn find_all_regex(&self, regex: &Regex) -> Vec<(usize, usize)> {
+        let mut matches = Vec::new();
+        let (mut byte_index, mut char_index) = (0, 0);
+        for (start, end) in regex.find_iter(&self.text) {
+            let start_index = char_index + self.text[byte_index..start].chars().count();
+            let end_index = start_index + self.text[start..end].chars().count();
+            matches.push((start_index, end_index));
+            byte_index = end;
+            char_index = end_index;
+        }
+        matches
+    }
+    pub fn iter(&self) -> Iter {
+        Iter {
+            chars: self.text.chars(),
+            spans: self.spans.iter(),
+            current: None
+        }
+    }
+    // Gets how many bytes of heap memory the string holds on to.
+    pub fn heap_size(&self) -> usize {
+        self.text.capacity() +
+            self.spans.capacity() * mem::size_of::<(usize, Format)>()
+    }
+    // Iterates over runs of text that share the same format.
+    pub fn spans(&self) -> Spans {
+        Spans {
+            text: &self.text,
+            spans: self.spans.iter()
+        }
+    }
+    // Helper function to add formatting for text that was just added.
+    fn add_span(&mut self, count: usize, format: Format) {
+        if count == 0 {
+            return;
+        }
+        self.len += count;
+        if let Some(&mut (ref mut last_count, last_format)) = self.spans.last_mut() {
+            if last_format == format {
+                *last_count += count;
+                return;
+            }
+        }
+        self.spans.push((count, format));
+    }
+    // Helper function to convert a character index to a byte index.
+    fn byte_index(&self, index: usize) -> usize {
+        match self.text.char_indices().nth(index) {
+            Some((b, _)) => b,
+            None => self.text.len()
+        }
+    }
+}
+
+pub struct Iter<'a> {
+    chars: Chars<'a>,
+    spans: slice::Iter<'a, (usize, Format)>,
+    // The format of the current run and the number of characters left in it.
+    current: Option<(usize, Format)>
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (char, Format);
+    fn next(&mut self) -> Option<(char, Format)> {
+        let format = match self.current {
+            Some((remaining, format)) if remaining > 0 => {
+                self.current = Some((remaining - 1, format));
+                format
+            },
+            _ => match self.spans.next() {
+                Some(&(count, format)) => {
+                    self.current = Some((count - 1, format));
+                    format
+                },
+                None => return None
+            }
+        };
+        self.chars.next().map(|ch| (ch, format))
+    }
+}
+
+pub struct Spans<'a> {
+    text: &'a str,
+    spans: slice::Iter<'a, (usize, Format)>
+}
+
+impl<'a> Iterator for Spans<'a> {
+    type Item = (&'a str, Format);
+    fn next(&mut self) -> Option<(&'a str, Format)> {
+        self.spans.next().map(|&(count, format)| {
+            let end = match self.text.char_indices().nth(count) {
+                Some((b, _)) => b,
+                None => self.text.len()
+            };
+            let (span_text, rest) = self.text.split_at(end);
+            self.text = rest;
+            (span_text, format)
+        })
+    }
+}
+
+impl<'a> IntoIterator for &'a FormattedString {
+    type Item = (char, Format);
+    type IntoIter = Iter<'a>;
+    fn into_iter(self) -> Iter<'a> { self.iter() }
+}
+
+impl IntoIterator for FormattedString {
+    type Item = (char, Format);
+    type IntoIter = vec::IntoIter<(char, Format)>;
+    fn into_iter(self) -> vec::IntoIter<(char, Format)> {
+        self.iter().collect::<Vec<_>>().into_iter()
+    }
+}
+
+impl FromIterator<(char, Format)> for FormattedString {
+    fn from_iter<I: IntoIterator<Item=(char, Format)>>(iter: I) -> FormattedString {
+        let mut fs = FormattedString::new();
+        fs.extend(iter);
+        fs
+    }
+}
+
+impl Extend<(char, Format)> for FormattedString {
+    fn extend<I: IntoIterator<Item=(char, Format)>>(&mut self, iter: I) {
+        for c in iter {
+            self.push(c);
+        }
+    }
+}
 
 pub fn with_format(s: &str, format: Format) -> FormattedString {
-    // TODO: clean this up.
-	let mut string = String::new();
-    string.push_str(s);
     let mut fs = FormattedString::new();
-    for ch in string.chars() {
-        fs.push((ch, format));
-    }
+    fs.push_str(s, format);
     fs
 }
 
@@ -53,37 +470,582 @@ pub fn with_color(s: &str, color: Color) -> FormattedString {
     with_format(s, Format::with_fg(color))
 }
 
-pub fn from_markup(s: &str) -> FormattedString {
-    let mut fs = Vec::new();
+// Converts markup into a formatted string. Formatting codes start with {:
+//   {{                      a literal {
+//   {x                      back to the default format
+//   {b {r {g {y {u {m {c {w foreground black, red, green, yellow, blue,
+//                           magenta, cyan or white; {d is the default
+//   {B {R {G {Y {U {M {C {W the same for the background; {D is the default
+//   {(N) {(#rrggbb)         foreground from the 256 color palette (0-255)
+//                           or as hex RGB
+//   {[N] {[#rrggbb]         the same for the background
+//   {* {/ {_                toggle bold, italic or underline
+//   {. {! {^ {-             toggle dim, blink, reverse or strikethrough
+//   {h                      highlight (turn reverse on, not toggle it)
+// Anything else after { is an error.
+pub fn from_markup(s: &str) -> Result<FormattedString, String> {
+    parse_markup(s).map(|(fs, _)| fs)
+}
+
+// Gets the format that markup codes alone (e.g. "{R{*") would give text.
+pub fn format_from_markup(codes: &str) -> Result<Format, String> {
+    let (fs, format) = try!(parse_markup(codes));
+    match fs.len() {
+        0 => Ok(format),
+        _ => Err(format!("Expected only markup codes: {}", codes))
+    }
+}
+
+// Helper function to parse markup, giving the formatted string and the
+// format in effect at the end of it.
+fn parse_markup(s: &str) -> Result<(FormattedString, Format), String> {
+    let mut fs = FormattedString::new();
     let mut format = Format::default();
-    let mut escape = false;
-    for c in s.chars() {
-        if escape {
-            match c {
-                '{' => fs.push((c, format)),
-                'x' => format = Format::default(),
-                'b' => format.fg_color = Color::Black,
-                'r' => format.fg_color = Color::Red,
-                'g' => format.fg_color = Color::Green,
-                'y' => format.fg_color = Color::Yellow,
-                'u' => format.fg_color = Color::Blue,
-                'm' => format.fg_color = Color::Magenta,
-                'c' => format.fg_color = Color::Cyan,
-                'w' => format.fg_color = Color::White,
-                'h' => format.style = Style::Standout,
-                _ => () // Unknown escape sequence.
-            }
-            escape = false;
-        } else if c == '{' {
-            escape = true;
-        } else {
+    let mut chars = s.chars().enumerate();
+    while let Some((i, c)) = chars.next() {
+        if c != '{' {
             fs.push((c, format));
+            continue;
+        }
+        let code = match chars.next() {
+            Some((_, code)) => code,
+            None => return Err(format!(
+                "Markup error at position {}: {{ at the end of the text", i))
+        };
+        match code {
+            '{' => fs.push((code, format)),
+            'x' => format = Format::default(),
+            '(' | '[' => {
+                let close = if code == '(' {')'} else {']'};
+                let mut spec = String::new();
+                let mut closed = false;
+                while let Some((_, ch)) = chars.next() {
+                    if ch == close {
+                        closed = true;
+                        break;
+                    }
+                    spec.push(ch);
+                }
+                let color = match color_from_spec(&spec) {
+                    Some(c) if closed => c,
+                    _ => return Err(format!(
+                        "Markup error at position {}: bad color {{{}{} (expected \
+                        0-255 or #rrggbb followed by {})", i, code, spec, close))
+                };
+                if code == '(' { format.fg_color = color; }
+                else { format.bg_color = color; }
+            },
+            '*' => format.style.bold = !format.style.bold,
+            '/' => format.style.italic = !format.style.italic,
+            '_' => format.style.underline = !format.style.underline,
+            '.' => format.style.dim = !format.style.dim,
+            '!' => format.style.blink = !format.style.blink,
+            '^' => format.style.reverse = !format.style.reverse,
+            'h' => format.style.reverse = true,
+            '-' => format.style.strikethrough = !format.style.strikethrough,
+            _ => match color_from_letter(code.to_ascii_lowercase()) {
+                Some(color) if code.is_lowercase() => format.fg_color = color,
+                Some(color) => format.bg_color = color,
+                None => return Err(format!(
+                    "Markup error at position {}: unknown code {{{}", i, code))
+            }
         }
     }
-    fs
+    Ok((fs, format))
+}
+
+// Helper function to get the color for a markup letter.
+fn color_from_letter(letter: char) -> Option<Color> {
+    match letter {
+        'd' => Some(Color::Default),
+        'b' => Some(Color::Black),
+        'r' => Some(Color::Red),
+        'g' => Some(Color::Green),
+        'y' => Some(Color::Yellow),
+        'u' => Some(Color::Blue),
+        'm' => Some(Color::Magenta),
+        'c' => Some(Color::Cyan),
+        'w' => Some(Color::White),
+        _ => None
+    }
+}
+
+// Helper function to parse a palette index or #rrggbb color from markup.
+fn color_from_spec(spec: &str) -> Option<Color> {
+    if spec.starts_with('#') {
+        if spec.len() != 7 || !spec[1..].chars().all(|c| c.is_digit(16)) {
+            return None;
+        }
+        let component = |i: usize| u8::from_str_radix(&spec[i..i + 2], 16).ok();
+        match (component(1), component(3), component(5)) {
+            (Some(r), Some(g), Some(b)) => Some(Color::Rgb(r, g, b)),
+            _ => None
+        }
+    } else {
+        spec.parse::<u8>().ok().map(Color::Indexed)
+    }
 }
 
 pub fn to_string(fs: &FormattedString) -> String {
-    let string: String = fs.iter().map(|&(ch, _)| ch).collect();
-    string
+    fs.as_str().to_string()
+}
+
+// Converts a formatted string into text with ANSI escape sequences (SGR) for
+// the formatting, as a terminal or esc_seq::interpret would understand it.
+pub fn to_ansi(fs: &FormattedString) -> String {
+    let mut ansi = String::new();
+    let mut current = Format::default();
+    for (text, format) in fs.spans() {
+        if format.style != current.style || format.fg_color != current.fg_color ||
+            format.bg_color != current.bg_color
+        {
+            ansi.push_str(&sgr_sequence(format));
+            current = format;
+        }
+        ansi.push_str(text);
+    }
+    if current.style != Style::normal() || current.fg_color != Color::Default ||
+        current.bg_color != Color::Default
+    {
+        ansi.push_str("\x1B[0m");
+    }
+    ansi
+}
+
+// Helper function to build the escape sequence for a format. Everything is
+// reset first, so the sequence doesn't depend on what came before it.
+fn sgr_sequence(format: Format) -> String {
+    let mut parts = vec!["0".to_string()];
+    let style = format.style;
+    let flags = [(style.bold, "1"), (style.dim, "2"), (style.italic, "3"),
+        (style.underline, "4"), (style.blink, "5"), (style.reverse, "7"),
+        (style.strikethrough, "9")];
+    for &(set, code) in flags.iter() {
+        if set { parts.push(code.to_string()); }
+    }
+    if let Some(p) = sgr_color(format.fg_color, 30, 90, 38) { parts.push(p); }
+    if let Some(p) = sgr_color(format.bg_color, 40, 100, 48) { parts.push(p); }
+    format!("\x1B[{}m", parts.join(";"))
+}
+
+// Helper function to get the SGR parameters for a color, given the codes
+// for the normal colors, the bright colors, and extended colors.
+fn sgr_color(color: Color, base: u32, bright_base: u32, extended: u32) -> Option<String> {
+    match color {
+        Color::Default => None,
+        Color::Indexed(n) => Some(format!("{};5;{}", extended, n)),
+        Color::Rgb(r, g, b) => Some(format!("{};2;{};{};{}", extended, r, g, b)),
+        c => c.palette_index().map(|i| if i < 8 {
+            format!("{}", base + i as u32)
+        } else {
+            format!("{}", bright_base + i as u32 - 8)
+        })
+    }
+}
+
+// Converts a formatted string into HTML, with the formatting as inline
+// styles. Whitespace is kept as is, so the result is meant to go inside a
+// <pre> element.
+pub fn to_html(fs: &FormattedString) -> String {
+    let mut html = String::new();
+    for (text, format) in fs.spans() {
+        let style = css_style(format);
+        if style.len() > 0 {
+            html.push_str(&format!("<span style=\"{}\">", style));
+        }
+        for ch in text.chars() {
+            match ch {
+                '&' => html.push_str("&amp;"),
+                '<' => html.push_str("&lt;"),
+                '>' => html.push_str("&gt;"),
+                '"' => html.push_str("&quot;"),
+                '\r' => (),
+                _ => html.push(ch)
+            }
+        }
+        if style.len() > 0 {
+            html.push_str("</span>");
+        }
+    }
+    html
+}
+
+// Helper function to build the CSS for a format.
+fn css_style(format: Format) -> String {
+    let mut properties = Vec::new();
+    let style = format.style;
+    let mut fg = css_color(format.fg_color);
+    let mut bg = css_color(format.bg_color);
+    if style.reverse {
+        // Swap the colors, using the page's own colors for the defaults.
+        let old_fg = fg.take().unwrap_or("CanvasText".to_string());
+        fg = Some(bg.take().unwrap_or("Canvas".to_string()));
+        bg = Some(old_fg);
+    }
+    if let Some(c) = fg { properties.push(format!("color:{}", c)); }
+    if let Some(c) = bg { properties.push(format!("background-color:{}", c)); }
+    if style.bold { properties.push("font-weight:bold".to_string()); }
+    if style.dim { properties.push("opacity:0.5".to_string()); }
+    if style.italic { properties.push("font-style:italic".to_string()); }
+
+    let mut decorations = Vec::new();
+    if style.underline || format.link.is_some() { decorations.push("underline"); }
+    if style.strikethrough { decorations.push("line-through"); }
+    if style.blink { decorations.push("blink"); }
+    if decorations.len() > 0 {
+        properties.push(format!("text-decoration:{}", decorations.join(" ")));
+    }
+    properties.join(";")
+}
+
+// Helper function to get the CSS for a color, or None for the default.
+fn css_color(color: Color) -> Option<String> {
+    let (r, g, b) = match color {
+        Color::Default => return None,
+        Color::Rgb(r, g, b) => (r, g, b),
+        c => match c.palette_index() {
+            Some(i) => palette_rgb(i),
+            None => return None
+        }
+    };
+    Some(format!("#{:02x}{:02x}{:02x}", r, g, b))
+}
+
+#[cfg(test)]
+mod tests {
+    use esc_seq;
+    use parse_state::ParseState;
+    use regex::Regex;
+    use super::*;
+
+    // Multibyte text split into runs with different formats, next to each
+    // other.
+    fn sample() -> Vec<(char, Format)> {
+        let red = Format::with_fg(Color::Red);
+        let blue = Format::with_fg(Color::Blue);
+        let mut chars = Vec::new();
+        chars.extend("h\u{e9}".chars().map(|c| (c, red)));
+        chars.extend("\u{6f22}\u{5b57}".chars().map(|c| (c, blue)));
+        chars.extend("\u{1f600}".chars().map(|c| (c, red)));
+        chars.extend("a\u{301}z".chars().map(|c| (c, Format::default())));
+        chars
+    }
+
+    fn build(chars: &[(char, Format)]) -> FormattedString {
+        chars.iter().cloned().collect()
+    }
+
+    // Checks that the spans cover the text and that neighbouring spans have
+    // been merged.
+    fn check_spans(fs: &FormattedString) {
+        assert_eq!(fs.text.chars().count(), fs.len);
+        assert_eq!(fs.spans.iter().map(|&(count, _)| count).sum::<usize>(), fs.len);
+        assert!(fs.spans.iter().all(|&(count, _)| count > 0));
+        for pair in fs.spans.windows(2) {
+            assert!(pair[0].1 != pair[1].1);
+        }
+    }
+
+    #[test]
+    fn adjacent_runs_are_merged() {
+        let red = Format::with_fg(Color::Red);
+        let mut fs = with_format("\u{e9}\u{e9}", red);
+        fs.push(('\u{6f22}', red));
+        fs.push_str("x", Format::default());
+        fs.append(with_format("y\u{1f600}", Format::default()));
+        check_spans(&fs);
+        assert_eq!(fs.spans, vec![(3, red), (3, Format::default())]);
+        assert_eq!(fs.len(), 6);
+    }
+
+    #[test]
+    fn iter_and_spans_match_text() {
+        let chars = sample();
+        let fs = build(&chars);
+        check_spans(&fs);
+        assert_eq!(fs.iter().collect::<Vec<_>>(), chars);
+        assert_eq!(fs.clone().into_iter().collect::<Vec<_>>(), chars);
+
+        let spans: Vec<(&str, Format)> = fs.spans().collect();
+        assert_eq!(spans.iter().map(|&(s, _)| s).collect::<String>(), fs.as_str());
+        assert_eq!(spans.len(), 4);
+        assert_eq!(spans[1].0, "\u{6f22}\u{5b57}");
+        assert_eq!(spans[3].0, "a\u{301}z");
+    }
+
+    #[test]
+    fn truncate_and_split_off_at_every_index() {
+        let chars = sample();
+        for i in 0..chars.len() + 2 {
+            let mut fs = build(&chars);
+            fs.truncate(i);
+            check_spans(&fs);
+            assert_eq!(fs, build(&chars[..cmp::min(i, chars.len())]));
+
+            let mut fs = build(&chars);
+            let after = fs.split_off(i);
+            check_spans(&fs);
+            check_spans(&after);
+            let i = cmp::min(i, chars.len());
+            assert_eq!(fs, build(&chars[..i]));
+            assert_eq!(after, build(&chars[i..]));
+        }
+    }
+
+    #[test]
+    fn slice_every_range() {
+        let chars = sample();
+        let fs = build(&chars);
+        for start in 0..chars.len() + 1 {
+            for end in start..chars.len() + 2 {
+                let slice = fs.slice(start, end);
+                check_spans(&slice);
+                assert_eq!(slice, build(&chars[start..cmp::min(end, chars.len())]));
+            }
+        }
+        assert!(fs.slice(3, 1).is_empty());
+    }
+
+    #[test]
+    fn map_format_every_range() {
+        let chars = sample();
+        let red = Format::with_fg(Color::Red);
+        for start in 0..chars.len() + 1 {
+            for end in start..chars.len() + 1 {
+                // Making text red merges it with the red runs next to it.
+                let mut fs = build(&chars);
+                fs.map_format(start, end, |_| red);
+                check_spans(&fs);
+                let expected: Vec<(char, Format)> = chars.iter().enumerate()
+                    .map(|(i, &(c, f))| (c, if i >= start && i < end {red} else {f}))
+                    .collect();
+                assert_eq!(fs, build(&expected));
+            }
+        }
+    }
+
+    #[test]
+    fn insert_and_remove() {
+        let chars = sample();
+        for i in 0..chars.len() {
+            let mut fs = build(&chars);
+            assert_eq!(fs.remove(i), chars[i]);
+            fs.insert(i, chars[i]);
+            check_spans(&fs);
+            assert_eq!(fs, build(&chars));
+            assert_eq!(fs.get(i), Some(chars[i]));
+        }
+        assert_eq!(build(&chars).get(chars.len()), None);
+    }
+
+    #[test]
+    fn searches_use_char_indices() {
+        let fs = build(&sample());
+        assert_eq!(fs.rfind('\u{1f600}'), Some(4));
+        assert_eq!(fs.rfind('z'), Some(7));
+        assert_eq!(fs.rfind('q'), None);
+
+        let regex = Regex::new("\u{5b57}.").unwrap();
+        assert_eq!(fs.find_regex(&regex), Some((3, 5)));
+        let regex = Regex::new("[\u{e9}z]").unwrap();
+        assert_eq!(fs.find_all_regex(&regex), vec![(1, 2), (7, 8)]);
+    }
+
+    // Helper function to read ANSI text back the way server output is read,
+    // starting from the default format.
+    fn from_ansi(ansi: &str) -> FormattedString {
+        let mut fs = FormattedString::new();
+        let mut state = ParseState::NotInProgress;
+        let mut format = Format::default();
+        for ch in ansi.chars() {
+            if let ParseState::NotInProgress = state {
+                if ch != '\x1B' {
+                    fs.push((ch, format));
+                    continue;
+                }
+            }
+            // Escape sequences are all ASCII.
+            state = esc_seq::parse(&state, ch as u8);
+            if let ParseState::Success(ref seq) = state {
+                assert!(esc_seq::is_sgr(seq));
+                format = esc_seq::interpret(seq, format);
+            }
+            if let ParseState::Error(ref seq) = state {
+                panic!("bad escape sequence: {:?}", seq);
+            }
+            if let ParseState::Success(_) = state {
+                state = ParseState::NotInProgress;
+            }
+        }
+        assert_eq!(state, ParseState::NotInProgress);
+        fs
+    }
+
+    #[test]
+    fn ansi_round_trip() {
+        let bold = Style { bold: true, .. Style::normal() };
+        let underline_reverse = Style { underline: true, reverse: true,
+            .. Style::normal() };
+        let reverse = Style { reverse: true, .. Style::normal() };
+        let all = Style { bold: true, dim: true, italic: true, underline: true,
+            blink: true, reverse: true, strikethrough: true };
+        let format = |style, fg_color, bg_color| Format { style: style,
+            fg_color: fg_color, bg_color: bg_color, link: None };
+
+        let mut fs = FormattedString::new();
+        fs.push_str("plain ", Format::default());
+        fs.push_str("bold red ", format(bold, Color::Red, Color::Default));
+        // Bold and the color turned off again.
+        fs.push_str("plain again ", Format::default());
+        fs.push_str("\u{e9}\u{6f22} ", format(Style::normal(), Color::Indexed(208),
+            Color::Indexed(17)));
+        fs.push_str("rgb ", format(Style::normal(), Color::Rgb(255, 128, 0),
+            Color::Rgb(0, 0, 64)));
+        fs.push_str("bright ", format(Style::normal(), Color::BrightCyan,
+            Color::BrightRed));
+        fs.push_str("bright white ", format(bold, Color::BrightWhite,
+            Color::BrightBlack));
+        fs.push_str("underlined ", format(underline_reverse, Color::Default,
+            Color::Default));
+        // Underline turned off, reverse kept.
+        fs.push_str("reversed ", format(reverse, Color::Default, Color::Default));
+        fs.push_str("everything", format(all, Color::Indexed(0), Color::White));
+        // Only the background turned off.
+        fs.push_str(" fg only", format(all, Color::Indexed(0), Color::Default));
+
+        let ansi = to_ansi(&fs);
+        assert!(ansi.ends_with("\x1B[0m"));
+        assert_eq!(from_ansi(&ansi), fs);
+        assert_eq!(to_ansi(&from_ansi(&ansi)), ansi);
+    }
+
+    #[test]
+    fn ansi_round_trip_from_server_output() {
+        // Attributes turned off one at a time, the way a server might.
+        let server = "\x1B[1;4;31mA\x1B[22mB\x1B[24mC\x1B[39mD\
+            \x1B[7;38;5;99;48;2;1;2;3mE\x1B[27;49mF\x1B[38:2:9:8:7mG\
+            \x1B[93;104mH\x1B[0mI";
+        let fs = from_ansi(server);
+        assert_eq!(fs.as_str(), "ABCDEFGHI");
+        let styles: Vec<Style> = fs.iter().map(|(_, f)| f.style).collect();
+        assert!(styles[0].bold && styles[0].underline);
+        assert!(!styles[1].bold && styles[1].underline);
+        assert!(!styles[2].underline);
+        assert!(styles[4].reverse && !styles[5].reverse);
+        let colors: Vec<(Color, Color)> = fs.iter()
+            .map(|(_, f)| (f.fg_color, f.bg_color)).collect();
+        assert_eq!(colors[2], (Color::Red, Color::Default));
+        assert_eq!(colors[3], (Color::Default, Color::Default));
+        assert_eq!(colors[4], (Color::Indexed(99), Color::Rgb(1, 2, 3)));
+        assert_eq!(colors[5], (Color::Indexed(99), Color::Default));
+        assert_eq!(colors[6], (Color::Rgb(9, 8, 7), Color::Default));
+        assert_eq!(colors[7], (Color::BrightYellow, Color::BrightBlue));
+        assert_eq!(colors[8], (Color::Default, Color::Default));
+
+        assert_eq!(from_ansi(&to_ansi(&fs)), fs);
+    }
+
+    #[test]
+    fn ansi_without_formatting_is_plain_text() {
+        let fs = with_format("<a & b>", Format::default());
+        assert_eq!(to_ansi(&fs), "<a & b>");
+        assert_eq!(to_ansi(&FormattedString::new()), "");
+    }
+
+    #[test]
+    fn html_escapes_special_characters() {
+        let fs = with_format("<b>\"fish\" & chips</b>\r\n", Format::default());
+        assert_eq!(to_html(&fs),
+            "&lt;b&gt;&quot;fish&quot; &amp; chips&lt;/b&gt;\n");
+
+        let mut fs = with_color("a<", Color::Red);
+        fs.push_str("&\"", Format::default());
+        let html = to_html(&fs);
+        assert!(html.starts_with("<span style=\""));
+        assert!(html.ends_with("a&lt;</span>&amp;&quot;"));
+    }
+
+    // Helper function to parse markup that's expected to be valid, giving
+    // the format of each character.
+    fn markup(s: &str) -> Vec<(char, Format)> {
+        from_markup(s).unwrap().iter().collect()
+    }
+
+    #[test]
+    fn markup_colors() {
+        let chars = markup("a{rb{Gc{dd{De");
+        assert_eq!(chars[0].1, Format::default());
+        assert_eq!(chars[1].1.fg_color, Color::Red);
+        assert_eq!((chars[2].1.fg_color, chars[2].1.bg_color), (Color::Red, Color::Green));
+        assert_eq!((chars[3].1.fg_color, chars[3].1.bg_color), (Color::Default, Color::Green));
+        assert_eq!(chars[4].1, Format::default());
+
+        let chars = markup("{(196)a{[#0080Ff]b{(#102030){[0]c{xd");
+        assert_eq!(chars[0].1.fg_color, Color::Indexed(196));
+        assert_eq!(chars[1].1.bg_color, Color::Rgb(0, 128, 255));
+        assert_eq!((chars[2].1.fg_color, chars[2].1.bg_color),
+            (Color::Rgb(16, 32, 48), Color::Indexed(0)));
+        assert_eq!(chars[3].1, Format::default());
+    }
+
+    #[test]
+    fn markup_style_toggles() {
+        let chars = markup("{*a{/b{_c{*d{/{_e{.{!{^{-f{.{!{^{-g");
+        let style = |i: usize| chars[i].1.style;
+        assert!(style(0).bold && !style(0).italic);
+        assert!(style(1).bold && style(1).italic);
+        assert!(style(2).bold && style(2).italic && style(2).underline);
+        assert!(!style(3).bold && style(3).italic && style(3).underline);
+        assert_eq!(style(4), Style::normal());
+        let f = style(5);
+        assert!(f.dim && f.blink && f.reverse && f.strikethrough);
+        assert_eq!(style(6), Style::normal());
+    }
+
+    #[test]
+    fn markup_highlight_sets_reverse() {
+        // Repeating {h keeps the text highlighted, unlike {^.
+        let chars = markup("{ha{hb{^c{hd");
+        let reversed: Vec<bool> = chars.iter().map(|&(_, f)| f.style.reverse).collect();
+        assert_eq!(reversed, vec![true, true, false, true]);
+    }
+
+    #[test]
+    fn markup_escapes() {
+        let fs = from_markup("{{r} {r{{").unwrap();
+        assert_eq!(fs.as_str(), "{r} {");
+        let chars: Vec<(char, Format)> = fs.iter().collect();
+        assert_eq!(chars[0].1, Format::default());
+        assert_eq!(chars[4].1.fg_color, Color::Red);
+        assert_eq!(from_markup("").unwrap(), FormattedString::new());
+    }
+
+    #[test]
+    fn markup_errors() {
+        assert_eq!(from_markup("ab{q"),
+            Err("Markup error at position 2: unknown code {q".to_string()));
+        assert_eq!(from_markup("abc{"),
+            Err("Markup error at position 3: { at the end of the text".to_string()));
+        assert_eq!(from_markup("\u{e9}{(256)x"), Err("Markup error at position 1: \
+            bad color {(256 (expected 0-255 or #rrggbb followed by ))".to_string()));
+        assert_eq!(from_markup("{[#12345]"), Err("Markup error at position 0: \
+            bad color {[#12345 (expected 0-255 or #rrggbb followed by ])".to_string()));
+        assert_eq!(from_markup("{(#gg0000)"), Err("Markup error at position 0: \
+            bad color {(#gg0000 (expected 0-255 or #rrggbb followed by ))".to_string()));
+        assert_eq!(from_markup("{(12"), Err("Markup error at position 0: \
+            bad color {(12 (expected 0-255 or #rrggbb followed by ))".to_string()));
+    }
+
+    #[test]
+    fn format_from_markup_codes() {
+        let format = format_from_markup("{R{*{(#ff0000)").unwrap();
+        assert_eq!((format.fg_color, format.bg_color), (Color::Rgb(255, 0, 0), Color::Red));
+        assert!(format.style.bold);
+        assert_eq!(format_from_markup("").unwrap(), Format::default());
+
+        assert_eq!(format_from_markup("{Ra"),
+            Err("Expected only markup codes: {Ra".to_string()));
+        assert_eq!(format_from_markup("{R{"),
+            Err("Markup error at position 2: { at the end of the text".to_string()));
+    }
 }
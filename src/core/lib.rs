@@ -1,16 +1,24 @@
+extern crate flate2;
 #[macro_use] extern crate lazy_static;
 #[macro_use] extern crate log;
 extern crate mio;
 extern crate regex;
+extern crate rustc_serialize;
 extern crate term;
 
+pub mod encoding;
 pub mod esc_seq;
 pub mod formatted_string;
 pub mod keys;
+pub mod mccp;
+pub mod msp;
+pub mod mxp;
 mod parse_state;
 mod ring_buffer;
 pub mod search;
 pub mod telnet;
+pub mod timer;
+pub mod trigger;
 
 pub use formatted_string::{FormattedString, Format, Color, Style};
 pub use parse_state::ParseState;
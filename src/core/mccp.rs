@@ -0,0 +1,83 @@
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress,
+    Status};
+
+// Amount of room to reserve in the output buffer for each pass through
+// zlib.
+const CHUNK_SIZE: usize = 4096;
+
+// Decompresses an incoming MCCP stream.
+pub struct Inflater {
+    stream: Decompress
+}
+
+impl Inflater {
+    pub fn new() -> Inflater {
+        Inflater { stream: Decompress::new(true) }
+    }
+
+    // Inflates as much of the input as possible. Returns the inflated data
+    // and, if the compressed stream ended within the input, the number of
+    // input bytes that belonged to it. Any bytes past that point are not
+    // compressed.
+    pub fn inflate(&mut self, input: &[u8]) ->
+        Result<(Vec<u8>, Option<usize>), String>
+    {
+        let mut output = Vec::with_capacity(CHUNK_SIZE);
+        let mut consumed = 0;
+        loop {
+            output.reserve(CHUNK_SIZE);
+            let total_in = self.stream.total_in();
+            let status = match self.stream.decompress_vec(&input[consumed..],
+                &mut output, FlushDecompress::None)
+            {
+                Ok(s) => s,
+                Err(e) => return Err(format!("{}", e))
+            };
+            consumed += (self.stream.total_in() - total_in) as usize;
+
+            match status {
+                Status::StreamEnd => return Ok((output, Some(consumed))),
+                Status::BufError => return Ok((output, None)),
+                Status::Ok => ()
+            }
+
+            // Stop once all of the input has been used and zlib has no more
+            // output to give.
+            if consumed >= input.len() && output.len() < output.capacity() {
+                return Ok((output, None));
+            }
+        }
+    }
+}
+
+// Compresses an outgoing MCCP stream.
+pub struct Deflater {
+    stream: Compress
+}
+
+impl Deflater {
+    pub fn new() -> Deflater {
+        Deflater { stream: Compress::new(Compression::default(), true) }
+    }
+
+    // Deflates the input, flushing so that the other side can decompress it
+    // immediately.
+    pub fn deflate(&mut self, input: &[u8]) -> Result<Vec<u8>, String> {
+        let mut output = Vec::with_capacity(input.len() + CHUNK_SIZE);
+        let mut consumed = 0;
+        loop {
+            output.reserve(CHUNK_SIZE);
+            let total_in = self.stream.total_in();
+            if let Err(e) = self.stream.compress_vec(&input[consumed..],
+                &mut output, FlushCompress::Sync)
+            {
+                return Err(format!("{}", e));
+            }
+            consumed += (self.stream.total_in() - total_in) as usize;
+
+            if consumed >= input.len() && output.len() < output.capacity() {
+                return Ok(output);
+            }
+        }
+    }
+}
@@ -0,0 +1,244 @@
+use formatted_string::Format;
+
+const SOUND_PREFIX: &'static str = "!!SOUND(";
+const MUSIC_PREFIX: &'static str = "!!MUSIC(";
+const TRIGGER_END: char = ')';
+
+// Longest trigger that will be buffered before giving up and treating it as
+// plain text.
+const MAX_TRIGGER_SIZE: usize = 512;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
+pub enum TriggerKind {
+    Sound,
+    Music
+}
+
+// A sound or music trigger embedded in the text from the server. A file of
+// "Off" means that whatever is playing should be stopped.
+#[derive(Debug, Eq, PartialEq, Clone, Hash)]
+pub struct Trigger {
+    pub kind: TriggerKind,
+    pub file: String,
+    pub volume: u32,
+    // The number of times to play, or -1 to repeat until stopped.
+    pub loops: i32,
+    // Only used for sounds; a sound with a higher priority interrupts others.
+    pub priority: u32,
+    // Only used for music; whether music that is already playing should
+    // continue rather than restart.
+    pub continue_playing: bool,
+    pub trigger_type: Option<String>,
+    pub url: Option<String>
+}
+
+impl Trigger {
+    fn new(kind: TriggerKind, file: &str) -> Trigger {
+        Trigger {
+            kind: kind,
+            file: file.to_string(),
+            volume: 100,
+            loops: 1,
+            priority: 50,
+            continue_playing: true,
+            trigger_type: None,
+            url: None
+        }
+    }
+}
+
+pub struct MspParser {
+    // Text held back because it may be the start of a trigger, along with
+    // the format of each character.
+    pending: String,
+    pending_formats: Vec<Format>
+}
+
+impl MspParser {
+    pub fn new() -> MspParser {
+        MspParser { pending: String::new(), pending_formats: Vec::new() }
+    }
+
+    // Parses the next character of text. Characters that aren't part of a
+    // trigger are added to out, possibly along with earlier ones that turned
+    // out not to be part of a trigger either.
+    pub fn parse(&mut self, ch: char, format: Format, out: &mut Vec<(char, Format)>)
+        -> Option<Trigger>
+    {
+        if self.pending.len() == 0 && ch != '!' {
+            out.push((ch, format));
+            return None;
+        }
+        self.pending.push(ch);
+        self.pending_formats.push(format);
+
+        // Check if this could still be the start of a trigger.
+        let prefix_len = SOUND_PREFIX.len();
+        if self.pending.len() <= prefix_len {
+            if !SOUND_PREFIX.starts_with(&self.pending[..]) &&
+                !MUSIC_PREFIX.starts_with(&self.pending[..])
+            {
+                self.flush(out);
+            }
+            return None;
+        }
+
+        if ch == TRIGGER_END {
+            let trigger = parse_trigger(&self.pending);
+            match trigger {
+                Some(_) => {
+                    self.pending.clear();
+                    self.pending_formats.clear();
+                },
+                None => self.flush(out)
+            }
+            trigger
+        } else {
+            if ch == '\n' || self.pending.len() > MAX_TRIGGER_SIZE {
+                self.flush(out);
+            }
+            None
+        }
+    }
+
+    // Checks whether any text is being held back.
+    pub fn has_pending(&self) -> bool {
+        self.pending.len() > 0
+    }
+
+    // Gives up on any text held back, e.g. when a prompt ends without the
+    // rest of a trigger arriving.
+    pub fn finish(&mut self, out: &mut Vec<(char, Format)>) {
+        while self.pending.len() > 0 {
+            self.flush(out);
+        }
+    }
+
+    // Helper function to give up on the pending text being a trigger. A
+    // trigger may still start later in the text, so everything after the
+    // first character is parsed again.
+    fn flush(&mut self, out: &mut Vec<(char, Format)>) {
+        let pending = self.pending.clone();
+        let formats = self.pending_formats.clone();
+        self.pending.clear();
+        self.pending_formats.clear();
+        let mut chars = pending.chars().zip(formats.into_iter());
+        if let Some(c) = chars.next() {
+            out.push(c);
+        }
+        for (ch, format) in chars {
+            self.parse(ch, format, out);
+        }
+    }
+}
+
+// Helper function to parse a full trigger, e.g.
+// !!SOUND(weather/rain.wav V=80 L=2 P=30 T=weather U=http://example.com/).
+fn parse_trigger(s: &str) -> Option<Trigger> {
+    let kind = if s.starts_with(SOUND_PREFIX) {
+        TriggerKind::Sound
+    } else if s.starts_with(MUSIC_PREFIX) {
+        TriggerKind::Music
+    } else {
+        return None;
+    };
+    let inside = &s[SOUND_PREFIX.len()..s.len() - 1];
+    let mut parts = inside.split_whitespace();
+    let mut trigger = match parts.next() {
+        Some(file) => Trigger::new(kind, file),
+        None => return None
+    };
+    for part in parts {
+        let (key, value) = match part.find('=') {
+            Some(i) => (part[..i].to_uppercase(), &part[i + 1..]),
+            None => {
+                warn!("Bad MSP parameter: {}", part);
+                continue;
+            }
+        };
+        let valid = match &key[..] {
+            "V" => value.parse().map(|v| trigger.volume = v).is_ok(),
+            "L" => value.parse().map(|l| trigger.loops = l).is_ok(),
+            "P" => value.parse().map(|p| trigger.priority = p).is_ok(),
+            "C" => value.parse::<u32>().map(|c| trigger.continue_playing = c != 0)
+                .is_ok(),
+            "T" => { trigger.trigger_type = Some(value.to_string()); true },
+            "U" => { trigger.url = Some(value.to_string()); true },
+            _ => false
+        };
+        if !valid {
+            warn!("Bad MSP parameter: {}", part);
+        }
+    }
+    Some(trigger)
+}
+
+#[cfg(test)]
+mod tests {
+    use formatted_string::{Color, Format};
+    use super::*;
+
+    fn parse_all(parser: &mut MspParser, s: &str, format: Format) ->
+        (Vec<(char, Format)>, Vec<Trigger>)
+    {
+        let mut out = Vec::new();
+        let mut triggers = Vec::new();
+        for ch in s.chars() {
+            if let Some(t) = parser.parse(ch, format, &mut out) {
+                triggers.push(t);
+            }
+        }
+        (out, triggers)
+    }
+
+    fn text(out: &[(char, Format)]) -> String {
+        out.iter().map(|&(ch, _)| ch).collect()
+    }
+
+    #[test]
+    fn triggers_are_removed() {
+        let mut parser = MspParser::new();
+        let (out, triggers) = parse_all(&mut parser,
+            "a!!SOUND(rain.wav V=80 L=2)b", Format::default());
+        assert_eq!(text(&out), "ab");
+        assert_eq!(triggers.len(), 1);
+        assert_eq!(triggers[0].file, "rain.wav");
+        assert_eq!((triggers[0].volume, triggers[0].loops), (80, 2));
+    }
+
+    #[test]
+    fn triggers_can_span_calls() {
+        let mut parser = MspParser::new();
+        let (out, _) = parse_all(&mut parser, "x!!MUS", Format::default());
+        assert_eq!(text(&out), "x");
+        assert!(parser.has_pending());
+        let (out, triggers) = parse_all(&mut parser, "IC(song.mid)", Format::default());
+        assert_eq!(text(&out), "");
+        assert_eq!(triggers[0].kind, TriggerKind::Music);
+    }
+
+    #[test]
+    fn held_text_keeps_its_format() {
+        let mut parser = MspParser::new();
+        let red = Format { fg_color: Color::Red, ..Format::default() };
+        let (mut out, _) = parse_all(&mut parser, "hi!", red);
+        assert_eq!(text(&out), "hi");
+        parser.finish(&mut out);
+        assert!(!parser.has_pending());
+        assert_eq!(out, vec![('h', red), ('i', red), ('!', red)]);
+
+        // Text that turns out not to be a trigger also keeps its format.
+        let (mut out, _) = parse_all(&mut parser, "!!", red);
+        out.extend(parse_all(&mut parser, "no", Format::default()).0);
+        assert_eq!(out, vec![('!', red), ('!', red), ('n', Format::default()),
+            ('o', Format::default())]);
+    }
+
+    #[test]
+    fn finish_releases_partial_triggers() {
+        let mut parser = MspParser::new();
+        let (mut out, _) = parse_all(&mut parser, "!!!SOUND(x", Format::default());
+        parser.finish(&mut out);
+        assert_eq!(text(&out), "!!!SOUND(x");
+    }
+}
@@ -0,0 +1,722 @@
+use formatted_string::{Color, Format, FormattedString, Style};
+use std::char;
+use std::collections::{HashMap, VecDeque};
+
+// Longest tag or entity that will be buffered before giving up and treating
+// it as plain text.
+const MAX_TAG_SIZE: usize = 1024;
+const MAX_ENTITY_SIZE: usize = 32;
+
+// How deeply custom elements can be nested inside each other's definitions.
+const MAX_ELEMENT_DEPTH: usize = 16;
+
+// MXP line modes, as set by ESC[<n>z.
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
+pub enum LineMode {
+    Open,
+    Secure,
+    Locked
+}
+
+// A clickable link created by a <send> or <a> tag. Formats refer to links by
+// an id, which can be looked up in a LinkTable.
+#[derive(Debug, Eq, PartialEq, Clone, Hash)]
+pub struct Link {
+    pub href: String,
+    pub hint: Option<String>,
+    pub prompt: bool
+}
+
+// The most recent links, by id. Older links are forgotten so that a long
+// session doesn't hold on to every link it has seen.
+pub struct LinkTable {
+    links: HashMap<usize, Link>,
+    // Ids in the order they were added, oldest first.
+    order: VecDeque<usize>,
+    max_links: usize
+}
+
+impl LinkTable {
+    pub fn new(max_links: usize) -> LinkTable {
+        LinkTable {
+            links: HashMap::new(),
+            order: VecDeque::new(),
+            max_links: max_links
+        }
+    }
+    pub fn insert(&mut self, id: usize, link: Link) {
+        if self.links.insert(id, link).is_none() {
+            self.order.push_back(id);
+        }
+        while self.order.len() > self.max_links {
+            if let Some(old) = self.order.pop_front() {
+                self.links.remove(&old);
+            }
+        }
+    }
+    pub fn get(&self, id: usize) -> Option<&Link> {
+        self.links.get(&id)
+    }
+    pub fn len(&self) -> usize {
+        self.links.len()
+    }
+}
+
+// A custom element defined with <!ELEMENT>.
+#[derive(Debug, Clone)]
+struct Element {
+    tags: Vec<String>,
+    attributes: Vec<(String, String)>,
+    open: bool
+}
+
+// A tag that is in effect for the text that follows it.
+#[derive(Debug, Clone)]
+struct OpenTag {
+    name: String,
+    // Tags opened in secure mode aren't closed at the end of the line.
+    secure: bool,
+    // Attributes added to those already in effect.
+    style: Style,
+    fg_color: Option<Color>,
+    bg_color: Option<Color>,
+    link: Option<(usize, Link)>,
+    // Text collected for links that refer to their own text.
+    text: Option<String>
+}
+
+impl OpenTag {
+    fn new(name: &str, secure: bool) -> OpenTag {
+        OpenTag {
+            name: name.to_string(),
+            secure: secure,
+            style: Style::normal(),
+            fg_color: None,
+            bg_color: None,
+            link: None,
+            text: None
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+enum ParseMode {
+    Text,
+    // The tag so far, along with the quote character if inside a quoted value.
+    Tag(String, Option<char>),
+    Entity(String)
+}
+
+pub struct MxpParser {
+    mode: LineMode,
+    default_mode: LineMode,
+    temp_secure: bool,
+    parse_mode: ParseMode,
+    stack: Vec<OpenTag>,
+    elements: HashMap<String, Element>,
+    entities: HashMap<String, String>,
+    // Custom elements currently being expanded, to catch definitions that
+    // refer to each other.
+    expanding: Vec<String>,
+    // Identifies the next link, so that adjacent links stay distinct.
+    next_link: usize,
+    // Links whose tags have been closed, waiting to be taken.
+    links: Vec<(usize, Link)>
+}
+
+impl MxpParser {
+    pub fn new() -> MxpParser {
+        MxpParser {
+            mode: LineMode::Open,
+            default_mode: LineMode::Open,
+            temp_secure: false,
+            parse_mode: ParseMode::Text,
+            stack: Vec::new(),
+            elements: HashMap::new(),
+            entities: HashMap::new(),
+            expanding: Vec::new(),
+            next_link: 0,
+            links: Vec::new()
+        }
+    }
+
+    // Starts over for a new connection. Links keep being numbered from where
+    // they left off, so that new links aren't mistaken for old ones still in
+    // the scrollback.
+    pub fn reset(&mut self) {
+        let next_link = self.next_link;
+        *self = MxpParser::new();
+        self.next_link = next_link;
+    }
+
+    // Takes the links that are complete, along with their ids.
+    pub fn take_links(&mut self) -> Vec<(usize, Link)> {
+        self.links.drain(..).collect()
+    }
+
+    // Handles an ESC[<n>z line mode change.
+    pub fn set_line_mode(&mut self, n: u32) {
+        match n {
+            0 => self.mode = LineMode::Open,
+            1 => self.mode = LineMode::Secure,
+            2 => self.mode = LineMode::Locked,
+            3 => {
+                // Reset.
+                self.stack.clear();
+                self.default_mode = LineMode::Open;
+                self.mode = LineMode::Open;
+                self.temp_secure = false;
+            },
+            4 => self.temp_secure = true,
+            5 => {
+                self.default_mode = LineMode::Open;
+                self.mode = LineMode::Open;
+            },
+            6 => {
+                self.default_mode = LineMode::Secure;
+                self.mode = LineMode::Secure;
+            },
+            7 => {
+                self.default_mode = LineMode::Locked;
+                self.mode = LineMode::Locked;
+            },
+            _ => warn!("Unknown MXP line mode: {}", n)
+        }
+    }
+
+    // Parses the next character of text, adding anything that should be
+    // displayed to out. The format is the one set by escape sequences, which
+    // MXP tags are applied on top of.
+    pub fn parse(&mut self, ch: char, format: Format, out: &mut FormattedString) {
+        let parse_mode = self.parse_mode.clone();
+        match parse_mode {
+            ParseMode::Text => {
+                let locked = self.mode == LineMode::Locked && !self.temp_secure;
+                match ch {
+                    '<' if !locked =>
+                        self.parse_mode = ParseMode::Tag(String::new(), None),
+                    '&' if !locked =>
+                        self.parse_mode = ParseMode::Entity(String::new()),
+                    '\n' => {
+                        self.emit(ch, format, out);
+                        self.end_line();
+                    },
+                    _ => self.emit(ch, format, out)
+                }
+            },
+            ParseMode::Tag(mut tag, quote) => {
+                match quote {
+                    Some(q) => {
+                        tag.push(ch);
+                        let quote = if ch == q {None} else {Some(q)};
+                        self.parse_mode = ParseMode::Tag(tag, quote);
+                    },
+                    None if ch == '"' || ch == '\'' => {
+                        tag.push(ch);
+                        self.parse_mode = ParseMode::Tag(tag, Some(ch));
+                    },
+                    None if ch == '>' => {
+                        self.parse_mode = ParseMode::Text;
+                        self.handle_tag(&tag, format, out);
+                    },
+                    _ if ch == '\n' || tag.len() > MAX_TAG_SIZE => {
+                        // Not really a tag.
+                        self.parse_mode = ParseMode::Text;
+                        self.emit('<', format, out);
+                        for c in tag.chars() {
+                            self.emit(c, format, out);
+                        }
+                        self.parse(ch, format, out);
+                    },
+                    _ => {
+                        tag.push(ch);
+                        self.parse_mode = ParseMode::Tag(tag, quote);
+                    }
+                }
+            },
+            ParseMode::Entity(mut entity) => {
+                if ch == ';' {
+                    self.parse_mode = ParseMode::Text;
+                    let text = match self.lookup_entity(&entity) {
+                        Some(t) => t,
+                        None => format!("&{};", &entity)
+                    };
+                    for c in text.chars() {
+                        self.emit(c, format, out);
+                    }
+                } else if (ch.is_alphanumeric() || ch == '#' || ch == '_') &&
+                    entity.len() < MAX_ENTITY_SIZE
+                {
+                    entity.push(ch);
+                    self.parse_mode = ParseMode::Entity(entity);
+                } else {
+                    // Not really an entity.
+                    self.parse_mode = ParseMode::Text;
+                    self.emit('&', format, out);
+                    for c in entity.chars() {
+                        self.emit(c, format, out);
+                    }
+                    self.parse(ch, format, out);
+                }
+            }
+        }
+    }
+
+    // Helper function to add a character to the output with the formatting
+    // from any open tags.
+    fn emit(&mut self, ch: char, format: Format, out: &mut FormattedString) {
+        let mut format = format;
+        for tag in self.stack.iter_mut() {
+            format.style = format.style.union(tag.style);
+            if let Some(c) = tag.fg_color { format.fg_color = c; }
+            if let Some(c) = tag.bg_color { format.bg_color = c; }
+            if let Some((id, _)) = tag.link { format.link = Some(id); }
+            if let Some(ref mut t) = tag.text { t.push(ch); }
+        }
+        out.push((ch, format));
+    }
+
+    // Helper function to handle the end of a line. Tags opened outside of
+    // secure mode are closed, and any temporary line mode ends.
+    fn end_line(&mut self) {
+        while let Some(i) = self.stack.iter().position(|t| !t.secure) {
+            self.close_from(i);
+        }
+        self.mode = self.default_mode;
+        self.temp_secure = false;
+    }
+
+    fn handle_tag(&mut self, tag: &str, format: Format, out: &mut FormattedString) {
+        let secure = self.mode == LineMode::Secure || self.temp_secure;
+        self.temp_secure = false;
+        let tag = tag.trim();
+        if tag.starts_with("!--") {
+            // Comment.
+        } else if tag.starts_with('!') {
+            if secure {
+                self.handle_definition(&tag[1..]);
+            }
+        } else if tag.starts_with('/') {
+            let name = tag[1..].trim().to_lowercase();
+            match self.stack.iter().rposition(|t| t.name == name) {
+                Some(i) => self.close_from(i),
+                None => ()
+            }
+        } else {
+            let tokens = tokenize(tag);
+            if tokens.len() > 0 {
+                let name = tokens[0].1.to_lowercase();
+                self.open_tag(&name, &tokens[1..], secure, format, out);
+            }
+        }
+    }
+
+    fn open_tag(&mut self, name: &str, attributes: &[(Option<String>, String)],
+        secure: bool, format: Format, out: &mut FormattedString)
+    {
+        let mut tag = OpenTag::new(name, secure);
+        match name {
+            "b" | "bold" | "strong" | "h" | "high" => tag.style.bold = true,
+            "i" | "italic" | "em" => tag.style.italic = true,
+            "u" | "underline" => tag.style.underline = true,
+            "s" | "strikeout" => tag.style.strikethrough = true,
+            "c" | "color" => {
+                tag.fg_color = find_attribute(attributes, "fore", 0)
+                    .and_then(|c| color_from_name(&c));
+                tag.bg_color = find_attribute(attributes, "back", 1)
+                    .and_then(|c| color_from_name(&c));
+            },
+            "br" => {
+                self.emit('\n', format, out);
+                return;
+            },
+            "send" | "a" if secure => {
+                let href = find_attribute(attributes, "href", 0);
+                let hint = find_attribute(attributes, "hint", 1);
+                let prompt = attributes.iter()
+                    .any(|&(ref k, ref v)| k.is_none() && v.to_lowercase() == "prompt");
+
+                // Links without an href (or that refer to their text) are
+                // filled in once the tag is closed.
+                let collect_text = match href {
+                    Some(ref h) => h.contains("&text;"),
+                    None => true
+                };
+                if collect_text {
+                    tag.text = Some(String::new());
+                }
+                tag.link = Some((self.next_link, Link {
+                    href: href.unwrap_or(String::new()),
+                    hint: hint,
+                    prompt: prompt
+                }));
+                self.next_link = self.next_link.wrapping_add(1);
+            },
+            _ => {
+                let element = match self.elements.get(name) {
+                    Some(e) if e.open || secure => e.clone(),
+                    _ => return // Unknown or not allowed.
+                };
+
+                // Guard against elements that refer to themselves, directly or
+                // through other elements.
+                if self.expanding.len() >= MAX_ELEMENT_DEPTH ||
+                    self.expanding.iter().any(|e| e == name)
+                {
+                    warn!("Not expanding recursive MXP element: {}", name);
+                    return;
+                }
+
+                // Open the element itself so that closing it also closes the
+                // tags from its definition.
+                self.stack.push(tag);
+                self.expanding.push(name.to_string());
+                for t in element.tags.iter() {
+                    let expanded = substitute_attributes(t, &element.attributes,
+                        attributes);
+                    let tokens = tokenize(&expanded);
+                    if tokens.len() > 0 {
+                        let inner_name = tokens[0].1.to_lowercase();
+                        self.open_tag(&inner_name, &tokens[1..], secure,
+                            format, out);
+                    }
+                }
+                self.expanding.pop();
+                return;
+            }
+        }
+        self.stack.push(tag);
+    }
+
+    // Helper function to close the tag at the given stack index along with
+    // every tag opened after it.
+    fn close_from(&mut self, index: usize) {
+        for tag in self.stack.drain(index..) {
+            if let Some((id, mut link)) = tag.link {
+                if let Some(text) = tag.text {
+                    link.href = if link.href.len() == 0 {text}
+                        else {link.href.replace("&text;", &text)};
+                }
+                self.links.push((id, link));
+            }
+        }
+    }
+
+    fn handle_definition(&mut self, definition: &str) {
+        let tokens = tokenize(definition);
+        if tokens.len() < 2 {
+            return;
+        }
+        let kind = tokens[0].1.to_uppercase();
+        let name = tokens[1].1.to_lowercase();
+        let flags: Vec<String> = tokens[2..].iter()
+            .filter(|&&(ref k, _)| k.is_none())
+            .map(|&(_, ref v)| v.to_uppercase())
+            .collect();
+        let delete = flags.iter().any(|f| f == "DELETE");
+        match &kind[..] {
+            "ELEMENT" | "EL" => {
+                if delete {
+                    self.elements.remove(&name);
+                    return;
+                }
+                let tags = match tokens.get(2) {
+                    Some(&(None, ref d)) => split_tags(d),
+                    _ => Vec::new()
+                };
+                let attributes = match find_attribute(&tokens[2..], "att", 99) {
+                    Some(a) => tokenize(&a).into_iter()
+                        .map(|(k, v)| match k {
+                            Some(k) => (k.to_lowercase(), v),
+                            None => (v.to_lowercase(), String::new())
+                        })
+                        .collect(),
+                    None => Vec::new()
+                };
+                let open = flags.iter().any(|f| f == "OPEN");
+                self.elements.insert(name, Element {
+                    tags: tags,
+                    attributes: attributes,
+                    open: open
+                });
+            },
+            "ENTITY" | "EN" => {
+                if delete {
+                    self.entities.remove(&name);
+                    return;
+                }
+                if let Some(&(None, ref value)) = tokens.get(2) {
+                    self.entities.insert(name, value.clone());
+                }
+            },
+            _ => () // Other definitions aren't supported.
+        }
+    }
+
+    fn lookup_entity(&self, entity: &str) -> Option<String> {
+        if entity.starts_with("#x") || entity.starts_with("#X") {
+            return u32::from_str_radix(&entity[2..], 16).ok()
+                .and_then(char::from_u32)
+                .map(|c| c.to_string());
+        }
+        if entity.starts_with('#') {
+            return entity[1..].parse::<u32>().ok()
+                .and_then(char::from_u32)
+                .map(|c| c.to_string());
+        }
+        match entity {
+            "lt" => Some("<".to_string()),
+            "gt" => Some(">".to_string()),
+            "amp" => Some("&".to_string()),
+            "quot" => Some("\"".to_string()),
+            "apos" => Some("'".to_string()),
+            "nbsp" => Some(" ".to_string()),
+            _ => self.entities.get(&entity.to_lowercase()).cloned()
+        }
+    }
+}
+
+// Checks whether an escape sequence is an MXP line mode change (ESC[<n>z) and
+// returns the mode if so.
+pub fn line_mode(seq: &[u8]) -> Option<u32> {
+    if seq.len() < 4 || seq[0] != 0x1B || seq[1] != b'[' ||
+        seq[seq.len() - 1] != b'z'
+    {
+        return None;
+    }
+    match ::std::str::from_utf8(&seq[2..seq.len() - 1]) {
+        Ok(s) => s.parse::<u32>().ok(),
+        Err(_) => None
+    }
+}
+
+// Helper function to split a tag into whitespace-separated tokens, each of
+// which is either key=value or a bare value. Quotes around values are
+// removed.
+fn tokenize(s: &str) -> Vec<(Option<String>, String)> {
+    let mut tokens = Vec::new();
+    let mut key: Option<String> = None;
+    let mut current = String::new();
+    let mut quote: Option<char> = None;
+    let mut in_token = false;
+    for ch in s.chars() {
+        match quote {
+            Some(q) => {
+                if ch == q { quote = None; } else { current.push(ch); }
+            },
+            None => {
+                if ch == '"' || ch == '\'' {
+                    quote = Some(ch);
+                    in_token = true;
+                } else if ch.is_whitespace() {
+                    if in_token {
+                        tokens.push((key.take(), current.clone()));
+                        current.clear();
+                        in_token = false;
+                    }
+                } else if ch == '=' && key.is_none() && in_token {
+                    key = Some(current.to_lowercase());
+                    current.clear();
+                } else {
+                    current.push(ch);
+                    in_token = true;
+                }
+            }
+        }
+    }
+    if in_token {
+        tokens.push((key, current));
+    }
+    tokens
+}
+
+// Helper function to find an attribute by name, or by position among the
+// attributes given without names.
+fn find_attribute(attributes: &[(Option<String>, String)], name: &str,
+    position: usize) -> Option<String>
+{
+    for &(ref k, ref v) in attributes.iter() {
+        if k.as_ref().map(|k| &k[..]) == Some(name) {
+            return Some(v.clone());
+        }
+    }
+    attributes.iter()
+        .filter(|&&(ref k, _)| k.is_none())
+        .nth(position)
+        .map(|&(_, ref v)| v.clone())
+}
+
+// Helper function to split an element definition into its tags.
+fn split_tags(definition: &str) -> Vec<String> {
+    definition.split('<')
+        .filter_map(|t| t.find('>').map(|end| t[..end].trim().to_string()))
+        .filter(|t| t.len() > 0)
+        .collect()
+}
+
+// Helper function to fill in the attributes of a custom element's tag.
+fn substitute_attributes(tag: &str, declared: &[(String, String)],
+    given: &[(Option<String>, String)]) -> String
+{
+    let mut result = tag.to_string();
+    for (i, &(ref name, ref default)) in declared.iter().enumerate() {
+        let value = find_attribute(given, name, i)
+            .unwrap_or(default.clone());
+        result = result.replace(&format!("&{};", name), &value);
+    }
+    result
+}
+
+// Helper function to convert an MXP color (a name or #RRGGBB) to a color.
+fn color_from_name(name: &str) -> Option<Color> {
+    let name = name.trim().to_lowercase();
+    if name.starts_with('#') {
+        // Servers can send anything here, so check before slicing.
+        if name.len() != 7 || !name[1..].chars().all(|c| c.is_digit(16)) {
+            return None;
+        }
+        let component = |i: usize| u8::from_str_radix(&name[i..i + 2], 16).ok();
+        return match (component(1), component(3), component(5)) {
+            (Some(r), Some(g), Some(b)) => Some(Color::Rgb(r, g, b)),
+            _ => None
+        };
+    }
+    match &name[..] {
+        "black" => Some(Color::Black),
+        "red" | "maroon" => Some(Color::Red),
+        "green" | "lime" => Some(Color::Green),
+        "yellow" | "olive" | "orange" => Some(Color::Yellow),
+        "blue" | "navy" => Some(Color::Blue),
+        "magenta" | "purple" | "fuchsia" => Some(Color::Magenta),
+        "cyan" | "teal" | "aqua" => Some(Color::Cyan),
+        "white" | "silver" | "gray" | "grey" => Some(Color::White),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use formatted_string::{Color, Format, FormattedString};
+    use super::*;
+
+    fn parse_all(parser: &mut MxpParser, s: &str) -> FormattedString {
+        let mut out = FormattedString::new();
+        for ch in s.chars() {
+            parser.parse(ch, Format::default(), &mut out);
+        }
+        out
+    }
+
+    #[test]
+    fn hex_colors() {
+        let mut parser = MxpParser::new();
+        let out = parse_all(&mut parser, "<color fore=\"#FF8000\">x</color>y");
+        assert_eq!(out.as_str(), "xy");
+        assert_eq!(out.get(0).unwrap().1.fg_color, Color::Rgb(255, 128, 0));
+        assert_eq!(out.get(1).unwrap().1.fg_color, Color::Default);
+    }
+
+    #[test]
+    fn bad_hex_colors_are_ignored() {
+        let mut parser = MxpParser::new();
+        let out = parse_all(&mut parser,
+            "<color fore=\"#1\u{e9}234\">a</color><color fore=\"#12345g\">b</color>");
+        assert_eq!(out.as_str(), "ab");
+        assert!(out.iter().all(|(_, f)| f.fg_color == Color::Default));
+    }
+
+    #[test]
+    fn self_referring_elements_are_not_expanded() {
+        let mut parser = MxpParser::new();
+        parser.set_line_mode(6); // Secure by default.
+        let out = parse_all(&mut parser,
+            "<!ELEMENT foo '<bar><i>'><!ELEMENT bar '<foo><u>'><foo>x</foo>");
+        assert_eq!(out.as_str(), "x");
+        let style = out.get(0).unwrap().1.style;
+        assert!(style.italic && style.underline);
+
+        let out = parse_all(&mut parser, "<!ELEMENT baz '<baz><b>'><baz>y");
+        assert_eq!(out.as_str(), "y");
+        assert!(out.get(0).unwrap().1.style.bold);
+    }
+
+    #[test]
+    fn adjacent_links_are_distinct() {
+        let mut parser = MxpParser::new();
+        parser.set_line_mode(6);
+        let out = parse_all(&mut parser, "<send>a</send><send>b</send>c");
+        let links: Vec<Option<usize>> = out.iter().map(|(_, f)| f.link).collect();
+        assert!(links[0].is_some() && links[1].is_some());
+        assert!(links[0] != links[1]);
+        assert_eq!(links[2], None);
+    }
+
+    #[test]
+    fn links_keep_their_targets() {
+        let mut parser = MxpParser::new();
+        parser.set_line_mode(6);
+        let out = parse_all(&mut parser,
+            "<send href=\"look &text;\" hint=\"Look at it\">chest</send> \
+            <a>north</a> <send \"buy sword\" \"Buy it\" prompt>buy</send>");
+        assert_eq!(out.as_str(), "chest north buy");
+        let links = parser.take_links();
+        assert_eq!(links.len(), 3);
+        assert_eq!(out.get(0).unwrap().1.link, Some(links[0].0));
+        assert_eq!(out.get(6).unwrap().1.link, Some(links[1].0));
+
+        assert_eq!(links[0].1, Link {
+            href: "look chest".to_string(),
+            hint: Some("Look at it".to_string()),
+            prompt: false
+        });
+        assert_eq!(links[1].1.href, "north");
+        assert_eq!(links[1].1.hint, None);
+        assert_eq!(links[2].1, Link {
+            href: "buy sword".to_string(),
+            hint: Some("Buy it".to_string()),
+            prompt: true
+        });
+        assert!(parser.take_links().is_empty());
+    }
+
+    #[test]
+    fn links_are_only_made_in_secure_mode() {
+        let mut parser = MxpParser::new();
+        let out = parse_all(&mut parser, "<send href=\"quit\">x</send>\n");
+        assert_eq!(out.as_str(), "x\n");
+        assert_eq!(out.get(0).unwrap().1.link, None);
+        assert!(parser.take_links().is_empty());
+    }
+
+    #[test]
+    fn reset_keeps_numbering_links() {
+        let mut parser = MxpParser::new();
+        parser.set_line_mode(6);
+        parse_all(&mut parser, "<send>a</send>");
+        parser.reset();
+        parser.set_line_mode(6);
+        parse_all(&mut parser, "<send>b</send>");
+        let links = parser.take_links();
+        assert_eq!(links.len(), 1);
+        assert!(links[0].0 != 0);
+    }
+
+    #[test]
+    fn link_table_forgets_the_oldest_links() {
+        let link = |href: &str| Link {
+            href: href.to_string(),
+            hint: None,
+            prompt: false
+        };
+        let mut table = LinkTable::new(2);
+        table.insert(1, link("a"));
+        table.insert(2, link("b"));
+        table.insert(2, link("c"));
+        assert_eq!(table.len(), 2);
+        table.insert(3, link("d"));
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.get(1), None);
+        assert_eq!(table.get(2).map(|l| &l.href[..]), Some("c"));
+        assert_eq!(table.get(3).map(|l| &l.href[..]), Some("d"));
+    }
+}
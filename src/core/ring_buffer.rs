@@ -6,7 +6,9 @@ use std::slice::{Iter, IterMut};
 pub struct RingBuffer<T> {
     data: Vec<T>,
     max_elements: Option<usize>,
-    next_index: usize
+    next_index: usize,
+    // The number of elements ever pushed, including overwritten ones.
+    total_pushed: usize
 }
 
 impl<T> RingBuffer<T> {
@@ -15,9 +17,11 @@ impl<T> RingBuffer<T> {
             Some(max) => Vec::with_capacity(max),
             None => Vec::new()
         };
-        RingBuffer {data: data, max_elements: max_elements, next_index: 0}
+        RingBuffer {data: data, max_elements: max_elements, next_index: 0,
+            total_pushed: 0}
     }
     pub fn len(&self) -> usize { self.data.len() }
+    pub fn total_pushed(&self) -> usize { self.total_pushed }
     pub fn push(&mut self, element: T) {
         // Insert the element.
         if self.next_index >= self.len() {
@@ -31,6 +35,7 @@ impl<T> RingBuffer<T> {
             Some(max) => (self.next_index + 1) % max,
             None => self.next_index + 1
         };
+        self.total_pushed += 1;
     }
     pub fn get<'a>(&'a self, index: usize) -> &'a T {
         self.iter(index).next().expect("Index outside of expected range")
@@ -1,11 +1,14 @@
-use formatted_string::{self, FormattedString};
+use formatted_string::FormattedString;
 use regex::Regex;
 use ring_buffer::RingBuffer;
 use std::error::Error;
 
 #[derive(Copy, Clone)]
 pub struct SearchResult {
+    // Counted back from the most recent line, as of when the buffer had
+    // total_lines pushed to it. Lines that arrive later move it further back.
     pub line_number: usize,
+    pub total_lines: usize,
     pub begin_index: usize,
     pub end_index: usize
 }
@@ -21,11 +24,11 @@ pub fn search_buffer(buffer: &RingBuffer<FormattedString>, search_str: &str,
 
     // Search through the buffer.
     for i in starting_line..buffer.len() {
-        let line = formatted_string::to_string(buffer.get_recent(i));
-        match regex.find(&line) {
+        match buffer.get_recent(i).find_regex(&regex) {
             Some((start, end)) => {
                 return Ok(Some(SearchResult {
                     line_number: i,
+                    total_lines: buffer.total_pushed(),
                     begin_index: start,
                     end_index: end
                 }))
@@ -0,0 +1,67 @@
+use encoding::Encoding;
+use super::{subnegotiation, CHARSET};
+
+// Subnegotiation commands.
+pub const REQUEST: u8 = 1;
+pub const ACCEPTED: u8 = 2;
+pub const REJECTED: u8 = 3;
+pub const TTABLE_IS: u8 = 4;
+pub const TTABLE_REJECTED: u8 = 5;
+pub const TTABLE_ACK: u8 = 6;
+pub const TTABLE_NAK: u8 = 7;
+
+const TTABLE_MARKER: &'static [u8] = b"[TTABLE]";
+
+// Parses the list of character sets offered in a REQUEST (not including the
+// REQUEST byte itself).
+pub fn parse_request(data: &[u8]) -> Vec<String> {
+    // Translation tables aren't supported, so skip over the marker and
+    // version if they are there.
+    let data = if data.starts_with(TTABLE_MARKER) {
+        &data[::std::cmp::min(TTABLE_MARKER.len() + 1, data.len())..]
+    } else {
+        data
+    };
+
+    // The first byte is the separator used between the names.
+    match data.split_first() {
+        Some((separator, names)) => names
+            .split(|b| b == separator)
+            .filter(|n| n.len() > 0)
+            .map(|n| String::from_utf8_lossy(n).into_owned())
+            .collect(),
+        None => Vec::new()
+    }
+}
+
+// Picks the character set to use from those offered, preferring UTF-8.
+pub fn choose(names: &[String]) -> Option<(String, Encoding)> {
+    let supported: Vec<(String, Encoding)> = names.iter()
+        .filter_map(|n| Encoding::from_name(n).map(|e| (n.clone(), e)))
+        .collect();
+    match supported.iter().find(|&&(_, e)| e == Encoding::Utf8) {
+        Some(s) => Some(s.clone()),
+        None => supported.into_iter().next()
+    }
+}
+
+// Asks the server to use one of the given character sets, in order of
+// preference.
+pub fn request(names: &[&str]) -> Vec<u8> {
+    let mut data = vec![REQUEST];
+    for name in names {
+        data.push(b';');
+        data.extend(name.bytes());
+    }
+    subnegotiation(CHARSET, &data)
+}
+
+pub fn accepted(name: &str) -> Vec<u8> {
+    let mut data = vec![ACCEPTED];
+    data.extend(name.bytes());
+    subnegotiation(CHARSET, &data)
+}
+
+pub fn rejected() -> Vec<u8> {
+    subnegotiation(CHARSET, &[REJECTED])
+}
@@ -0,0 +1,36 @@
+pub use rustc_serialize::json::Json;
+use super::{subnegotiation, GMCP};
+
+// Splits a GMCP message into its package name and JSON payload. Messages
+// without a payload are given a null payload.
+pub fn parse(data: &[u8]) -> Result<(String, Json), String> {
+    let message = match ::std::str::from_utf8(data) {
+        Ok(m) => m.trim(),
+        Err(e) => return Err(format!("GMCP message is not UTF-8: {}", e))
+    };
+    let (package, payload) = match message.find(char::is_whitespace) {
+        Some(i) => (&message[..i], message[i..].trim()),
+        None => (message, "")
+    };
+    if package.len() == 0 {
+        return Err("GMCP message is missing a package name".to_string());
+    }
+    if payload.len() == 0 {
+        return Ok((package.to_string(), Json::Null));
+    }
+    match Json::from_str(payload) {
+        Ok(json) => Ok((package.to_string(), json)),
+        Err(e) => Err(format!("Bad GMCP payload for {}: {}", package, e))
+    }
+}
+
+// Builds a GMCP subnegotiation for the given package and (already encoded)
+// JSON payload.
+pub fn message(package: &str, payload: &str) -> Vec<u8> {
+    let mut data = package.as_bytes().to_vec();
+    if payload.len() > 0 {
+        data.push(b' ');
+        data.extend(payload.bytes());
+    }
+    subnegotiation(GMCP, &data)
+}
@@ -0,0 +1,271 @@
+use parse_state::ParseState;
+
+pub mod charset;
+pub mod gmcp;
+pub mod msdp;
+pub mod mssp;
+pub mod options;
+pub mod ttype;
+
+pub use self::options::{OptionTable, OptionEvent};
+
+pub const EOR: u8 = 239; // End of record. Marks the end of a prompt.
+pub const SE: u8 = 240; // End of subnegotiation parameters.
+pub const NOP: u8 = 241; // No operation.
+pub const DATA_MARK: u8 = 242; // The data stream portion of a Synch. This should always be accompanied by a TCP Urgent notification.
+pub const BREAK: u8 = 243; // NVT character BRK.
+pub const IP: u8 = 244; // The function IP (interrupt process).
+pub const AO: u8 = 245; // The function AO (abort output).
+pub const AYT: u8 = 246; // The function AYT (are you there).
+pub const EC: u8 = 247; // The function EC (erase character).
+pub const EL: u8 = 248; // The function EL (erase line).
+pub const GA: u8 = 249; // The GA (go ahead) signal.
+pub const SB: u8 = 250; // Indicates that what follows is subnegotiation of the indicated option.
+pub const WILL: u8 = 251; // Indicates the desire to begin performing, or confirmation that you are now performing, the indicated option.
+pub const WONT: u8 = 252; // Indicates the refusal to perform, or continue performing, the indicated option.
+pub const DO: u8 = 253; // Indicates the request that the other party perform, or
+                      // confirmation that you are expecting the other party to perform, the
+                      // indicated option.
+pub const DONT: u8 = 254; // Indicates the demand that the other party stop performing,
+                        // or confirmation that you are no longer expecting the other party
+                        // to perform, the indicated option.
+pub const IAC: u8 = 255; // Interpret As Command. Indicates the start of a telnet option
+                       // negotiation.
+
+// Telnet options.
+pub const BINARY: u8 = 0; // Transmit binary.
+pub const ECHO: u8 = 1; // Echo.
+pub const SGA: u8 = 3; // Suppress go ahead.
+pub const TTYPE: u8 = 24; // Terminal type.
+pub const TELOPT_EOR: u8 = 25; // End of record.
+pub const NAWS: u8 = 31; // Negotiate about window size.
+pub const CHARSET: u8 = 42; // Character set.
+pub const MSDP: u8 = 69; // MUD server data protocol.
+pub const MSSP: u8 = 70; // MUD server status protocol.
+pub const COMPRESS2: u8 = 86; // MCCP2 (server to client compression).
+pub const COMPRESS3: u8 = 87; // MCCP3 (client to server compression).
+pub const MSP: u8 = 90; // MUD sound protocol.
+pub const MXP: u8 = 91; // MUD extension protocol.
+pub const GMCP: u8 = 0xC9;
+ 
+pub fn parse(old_state: &ParseState, byte: u8) -> ParseState {
+    match *old_state {
+        ParseState::NotInProgress => {
+            match byte {
+                IAC => ParseState::InProgress(vec![byte]),
+                _ => ParseState::NotInProgress
+            }
+        },
+        ParseState::InProgress(ref b) => {
+            let mut bytes = b.clone();
+            bytes.push(byte);
+
+            // Determine the next state.
+            match bytes.len() {
+                1 => ParseState::InProgress(bytes),
+                2 => {
+                    match byte {
+                        // Two byte commands.
+                        IAC | NOP | DATA_MARK | BREAK | IP | AO | AYT |
+                        EC | EL | GA | EOR =>
+                            ParseState::Success(bytes),
+
+                        // Three byte commands.
+                        WILL | WONT | DO | DONT | SB =>
+                            ParseState::InProgress(bytes),
+
+                        // Unknown command.
+                        _ => ParseState::Error(bytes)
+                    }
+                },
+                3 => {
+                    let prev_byte = bytes[bytes.len() - 2];
+                    match prev_byte {
+                        // Three byte commands.
+                        WILL | WONT | DO | DONT =>
+                            ParseState::Success(bytes),
+
+                        // Sub-negotiation can span an arbitrary number of
+                        // bytes.
+                        SB => ParseState::InProgress(bytes),
+
+                        // Unexpected command.
+                        _ => ParseState::Error(bytes)
+                    }
+                },
+                _ => {
+                    // Sub-negotiation is assumed, since that is the only
+                    // command that can be this long. Check if the most recent
+                    // bytes are IAC,SE. This ends sub-negotiation, unless the
+                    // IAC is the second half of an escaped IAC within the
+                    // data.
+                    let num_iacs = bytes[3..bytes.len() - 1].iter().rev()
+                        .take_while(|b| **b == IAC)
+                        .count();
+                    if byte == SE && num_iacs % 2 == 1 {
+                        ParseState::Success(bytes)
+                    } else {
+                        ParseState::InProgress(bytes)
+                    }
+                }
+            }
+        },
+        ParseState::Success(_) => parse(&ParseState::NotInProgress, byte),
+        ParseState::Error(_) => parse(&ParseState::NotInProgress, byte)
+    }
+}
+
+// Builds a subnegotiation command for the given option, escaping any IAC
+// bytes within the data.
+pub fn subnegotiation(option: u8, data: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![IAC, SB, option];
+    for byte in data {
+        if *byte == IAC {
+            bytes.push(IAC);
+        }
+        bytes.push(*byte);
+    }
+    bytes.push(IAC);
+    bytes.push(SE);
+    bytes
+}
+
+// Builds a NAWS subnegotiation reporting the given window size.
+pub fn naws(width: u16, height: u16) -> Vec<u8> {
+    subnegotiation(NAWS, &[(width >> 8) as u8, (width & 0xFF) as u8,
+        (height >> 8) as u8, (height & 0xFF) as u8])
+}
+
+// Splits a complete subnegotiation command (IAC SB <option> ... IAC SE) into
+// its option and data, un-escaping any doubled IAC bytes within the data.
+pub fn parse_subnegotiation(cmd: &[u8]) -> Option<(u8, Vec<u8>)> {
+    if cmd.len() < 5 || cmd[0] != IAC || cmd[1] != SB ||
+        cmd[cmd.len() - 2] != IAC || cmd[cmd.len() - 1] != SE
+    {
+        return None;
+    }
+
+    let mut data = Vec::new();
+    let mut prev_iac = false;
+    for byte in &cmd[3..cmd.len() - 2] {
+        if *byte == IAC && !prev_iac {
+            prev_iac = true;
+            continue;
+        }
+        prev_iac = false;
+        data.push(*byte);
+    }
+    Some((cmd[2], data))
+}
+
+// Encodes outgoing data for the telnet stream. IAC bytes are doubled and,
+// unless binary transmission has been negotiated, line endings are converted
+// to CR LF (with a lone CR sent as CR NUL).
+pub fn encode(data: &[u8], binary: bool) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(data.len());
+    for (i, byte) in data.iter().enumerate() {
+        match *byte {
+            IAC => {
+                bytes.push(IAC);
+                bytes.push(IAC);
+            },
+            b'\n' if !binary => {
+                if i == 0 || data[i - 1] != b'\r' {
+                    bytes.push(b'\r');
+                }
+                bytes.push(b'\n');
+            },
+            b'\r' if !binary => {
+                bytes.push(b'\r');
+                if data.get(i + 1) != Some(&b'\n') {
+                    bytes.push(0);
+                }
+            },
+            b => bytes.push(b)
+        }
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use parse_state::ParseState;
+    use super::*;
+
+    // Runs a stream through the parser, returning the data bytes and the
+    // subnegotiations found.
+    fn decode(stream: &[u8]) -> (Vec<u8>, Vec<(u8, Vec<u8>)>) {
+        let mut data = Vec::new();
+        let mut subnegotiations = Vec::new();
+        let mut state = ParseState::NotInProgress;
+        for byte in stream {
+            state = parse(&state, *byte);
+            match state {
+                ParseState::NotInProgress => data.push(*byte),
+                ParseState::Success(ref cmd) if &cmd[..] == &[IAC, IAC] =>
+                    data.push(IAC),
+                ParseState::Success(ref cmd) => {
+                    subnegotiations.push(parse_subnegotiation(cmd).unwrap())
+                },
+                ParseState::InProgress(_) => (),
+                ParseState::Error(ref cmd) => panic!("bad command: {:?}", cmd)
+            }
+        }
+        if let ParseState::InProgress(ref cmd) = state {
+            panic!("unfinished command: {:?}", cmd);
+        }
+        (data, subnegotiations)
+    }
+
+    #[test]
+    fn encode_round_trips_binary_data() {
+        let data: Vec<u8> = (0..256).map(|b| b as u8).collect();
+        assert_eq!(decode(&encode(&data, true)), (data, vec![]));
+    }
+
+    #[test]
+    fn encode_doubles_iac() {
+        let data = [b'a', IAC, IAC, b'b', IAC];
+        let encoded = encode(&data, true);
+        assert_eq!(encoded, vec![b'a', IAC, IAC, IAC, IAC, b'b', IAC, IAC]);
+        assert_eq!(decode(&encoded).0, data.to_vec());
+    }
+
+    #[test]
+    fn encode_converts_line_endings() {
+        let encoded = encode(b"a\nb\r\nc\rd", false);
+        assert_eq!(encoded, b"a\r\nb\r\nc\r\0d".to_vec());
+        assert_eq!(decode(&encoded).0, encoded);
+    }
+
+    #[test]
+    fn subnegotiation_round_trips_iac_in_payload() {
+        let payload = [1, IAC, 2, IAC, IAC, 3];
+        let (data, subnegotiations) = decode(&subnegotiation(GMCP, &payload));
+        assert!(data.is_empty());
+        assert_eq!(subnegotiations, vec![(GMCP, payload.to_vec())]);
+    }
+
+    #[test]
+    fn subnegotiation_round_trips_iac_before_se() {
+        // An escaped IAC right before the closing IAC SE.
+        let payload = [b'x', IAC];
+        let encoded = subnegotiation(MSDP, &payload);
+        assert_eq!(encoded, vec![IAC, SB, MSDP, b'x', IAC, IAC, IAC, SE]);
+        assert_eq!(decode(&encoded).1, vec![(MSDP, payload.to_vec())]);
+
+        // An escaped IAC followed by a data byte that happens to be SE.
+        let payload = [IAC, SE, b'y'];
+        assert_eq!(decode(&subnegotiation(MSDP, &payload)).1,
+            vec![(MSDP, payload.to_vec())]);
+    }
+
+    #[test]
+    fn data_and_subnegotiations_interleave() {
+        let mut stream = encode(&[b'a', IAC], true);
+        stream.extend(subnegotiation(GMCP, &[IAC]));
+        stream.extend(encode(b"b", true));
+        assert_eq!(decode(&stream),
+            (vec![b'a', IAC, b'b'], vec![(GMCP, vec![IAC])]));
+    }
+}
@@ -0,0 +1,113 @@
+use super::{subnegotiation, MSDP};
+
+pub const MSDP_VAR: u8 = 1;
+pub const MSDP_VAL: u8 = 2;
+pub const MSDP_TABLE_OPEN: u8 = 3;
+pub const MSDP_TABLE_CLOSE: u8 = 4;
+pub const MSDP_ARRAY_OPEN: u8 = 5;
+pub const MSDP_ARRAY_CLOSE: u8 = 6;
+
+#[derive(Debug, Eq, PartialEq, Clone, Hash)]
+pub enum MsdpValue {
+    String(String),
+    Array(Vec<MsdpValue>),
+    Table(Vec<(String, MsdpValue)>)
+}
+
+// Parses the data of an MSDP subnegotiation into its variables.
+pub fn parse(data: &[u8]) -> Result<Vec<(String, MsdpValue)>, String> {
+    let mut index = 0;
+    let variables = try!(parse_variables(data, &mut index));
+    if index < data.len() {
+        return Err(format!("Unexpected MSDP byte {} at {}", data[index], index));
+    }
+    Ok(variables)
+}
+
+// Builds an MSDP command (e.g. LIST, REPORT, SEND) with the given arguments.
+pub fn command(name: &str, args: &[String]) -> Vec<u8> {
+    let mut data = vec![MSDP_VAR];
+    data.extend(name.bytes());
+    for arg in args {
+        data.push(MSDP_VAL);
+        data.extend(arg.bytes());
+    }
+    subnegotiation(MSDP, &data)
+}
+
+// Helper function to parse VAR/VAL pairs until the end of the data or the end
+// of the enclosing table.
+fn parse_variables(data: &[u8], index: &mut usize) ->
+    Result<Vec<(String, MsdpValue)>, String>
+{
+    let mut variables = Vec::new();
+    while *index < data.len() && data[*index] != MSDP_TABLE_CLOSE {
+        if data[*index] != MSDP_VAR {
+            return Err(format!("Expected MSDP_VAR at {}", *index));
+        }
+        *index += 1;
+        let name = parse_string(data, index);
+
+        // Multiple values for a single variable are treated as an array.
+        let mut values = Vec::new();
+        while *index < data.len() && data[*index] == MSDP_VAL {
+            *index += 1;
+            values.push(try!(parse_value(data, index)));
+        }
+        let value = match values.len() {
+            0 => return Err(format!("Missing MSDP_VAL for {}", name)),
+            1 => values.pop().unwrap(),
+            _ => MsdpValue::Array(values)
+        };
+        variables.push((name, value));
+    }
+    Ok(variables)
+}
+
+// Helper function to parse the value following an MSDP_VAL.
+fn parse_value(data: &[u8], index: &mut usize) -> Result<MsdpValue, String> {
+    if *index >= data.len() {
+        return Ok(MsdpValue::String(String::new()));
+    }
+    match data[*index] {
+        MSDP_TABLE_OPEN => {
+            *index += 1;
+            let table = try!(parse_variables(data, index));
+            try!(expect_byte(data, index, MSDP_TABLE_CLOSE));
+            Ok(MsdpValue::Table(table))
+        },
+        MSDP_ARRAY_OPEN => {
+            *index += 1;
+            let mut array = Vec::new();
+            while *index < data.len() && data[*index] == MSDP_VAL {
+                *index += 1;
+                array.push(try!(parse_value(data, index)));
+            }
+            try!(expect_byte(data, index, MSDP_ARRAY_CLOSE));
+            Ok(MsdpValue::Array(array))
+        },
+        _ => Ok(MsdpValue::String(parse_string(data, index)))
+    }
+}
+
+// Helper function to read a string up to the next MSDP control byte.
+fn parse_string(data: &[u8], index: &mut usize) -> String {
+    let start = *index;
+    while *index < data.len() && !is_control_byte(data[*index]) {
+        *index += 1;
+    }
+    String::from_utf8_lossy(&data[start..*index]).into_owned()
+}
+
+fn expect_byte(data: &[u8], index: &mut usize, byte: u8) -> Result<(), String> {
+    if *index < data.len() && data[*index] == byte {
+        *index += 1;
+        Ok(())
+    } else {
+        Err(format!("Expected MSDP byte {} at {}", byte, *index))
+    }
+}
+
+fn is_control_byte(byte: u8) -> bool {
+    byte >= MSDP_VAR && byte <= MSDP_ARRAY_CLOSE
+}
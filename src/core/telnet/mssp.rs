@@ -0,0 +1,52 @@
+use rustc_serialize::json::Json;
+use std::collections::BTreeMap;
+
+pub const MSSP_VAR: u8 = 1;
+pub const MSSP_VAL: u8 = 2;
+
+// Parses the data of an MSSP subnegotiation into a map of variables to their
+// values. Variables can have more than one value.
+pub fn parse(data: &[u8]) -> Result<BTreeMap<String, Vec<String>>, String> {
+    let mut variables = BTreeMap::new();
+    let mut name: Option<String> = None;
+    let mut index = 0;
+    while index < data.len() {
+        let marker = data[index];
+        index += 1;
+        let start = index;
+        while index < data.len() && data[index] != MSSP_VAR &&
+            data[index] != MSSP_VAL
+        {
+            index += 1;
+        }
+        let text = String::from_utf8_lossy(&data[start..index]).into_owned();
+
+        match marker {
+            MSSP_VAR => {
+                variables.entry(text.clone()).or_insert(Vec::new());
+                name = Some(text);
+            },
+            MSSP_VAL => match name {
+                Some(ref n) => variables.get_mut(n).unwrap().push(text),
+                None => return Err("MSSP_VAL without MSSP_VAR".to_string())
+            },
+            b => return Err(format!("Unexpected MSSP byte {}", b))
+        }
+    }
+    Ok(variables)
+}
+
+// Converts MSSP variables into JSON, with variables that have more than one
+// value becoming arrays.
+pub fn to_json(variables: &BTreeMap<String, Vec<String>>) -> Json {
+    let mut object = BTreeMap::new();
+    for (name, values) in variables.iter() {
+        let value = if values.len() == 1 {
+            Json::String(values[0].clone())
+        } else {
+            Json::Array(values.iter().map(|v| Json::String(v.clone())).collect())
+        };
+        object.insert(name.clone(), value);
+    }
+    Json::Object(object)
+}
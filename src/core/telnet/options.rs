@@ -0,0 +1,393 @@
+use std::collections::HashMap;
+use super::{WILL, WONT, DO, DONT, IAC};
+
+// Negotiation state for one side of an option, as described by the
+// Q method of RFC 1143. The "Opposite" variants indicate that the
+// opposite request has been queued behind the one in flight.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+enum QState {
+    No,
+    Yes,
+    WantNo,
+    WantNoOpposite,
+    WantYes,
+    WantYesOpposite
+}
+
+#[derive(Debug, Copy, Clone)]
+struct OptionEntry {
+    // Whether we are performing the option.
+    local: QState,
+    // Whether the other side is performing the option.
+    remote: QState,
+    // Whether we are willing to perform the option when asked.
+    local_supported: bool,
+    // Whether we want the other side to perform the option when offered.
+    remote_supported: bool,
+    // Whether each side was last reported as enabled, so that a disabled
+    // event is only reported after an enabled one.
+    local_reported: bool,
+    remote_reported: bool
+}
+
+impl OptionEntry {
+    fn new() -> OptionEntry {
+        OptionEntry {
+            local: QState::No,
+            remote: QState::No,
+            local_supported: false,
+            remote_supported: false,
+            local_reported: false,
+            remote_reported: false
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
+pub enum OptionEvent {
+    LocalEnabled(u8),
+    LocalDisabled(u8),
+    RemoteEnabled(u8),
+    RemoteDisabled(u8)
+}
+
+#[derive(Debug)]
+pub struct OptionTable {
+    options: HashMap<u8, OptionEntry>
+}
+
+impl OptionTable {
+    pub fn new() -> OptionTable {
+        OptionTable { options: HashMap::new() }
+    }
+
+    // Marks an option as one we are willing to perform (answering DO
+    // with WILL).
+    pub fn support_local(&mut self, option: u8) {
+        self.entry(option).local_supported = true;
+    }
+
+    // Marks an option as one we want the other side to perform
+    // (answering WILL with DO).
+    pub fn support_remote(&mut self, option: u8) {
+        self.entry(option).remote_supported = true;
+    }
+
+    pub fn is_local_enabled(&self, option: u8) -> bool {
+        match self.options.get(&option) {
+            Some(e) => e.local == QState::Yes,
+            None => false
+        }
+    }
+
+    pub fn is_remote_enabled(&self, option: u8) -> bool {
+        match self.options.get(&option) {
+            Some(e) => e.remote == QState::Yes,
+            None => false
+        }
+    }
+
+    // Handles a WILL/WONT/DO/DONT received from the other side. Returns the
+    // reply to send back (if any) and the resulting change in option state
+    // (if any).
+    pub fn receive(&mut self, command: u8, option: u8) ->
+        (Option<Vec<u8>>, Option<OptionEvent>)
+    {
+        let entry = self.entry(option);
+        match command {
+            WILL | WONT => {
+                let (reply, changed) = if command == WILL {
+                    let supported = entry.remote_supported;
+                    receive_enable(&mut entry.remote, supported, DO, DONT)
+                } else {
+                    receive_disable(&mut entry.remote, DO, DONT)
+                };
+                (reply.map(|c| vec![IAC, c, option]),
+                    report(&mut entry.remote_reported, changed)
+                        .map(|e| if e {OptionEvent::RemoteEnabled(option)}
+                            else {OptionEvent::RemoteDisabled(option)}))
+            },
+            DO | DONT => {
+                let (reply, changed) = if command == DO {
+                    let supported = entry.local_supported;
+                    receive_enable(&mut entry.local, supported, WILL, WONT)
+                } else {
+                    receive_disable(&mut entry.local, WILL, WONT)
+                };
+                (reply.map(|c| vec![IAC, c, option]),
+                    report(&mut entry.local_reported, changed)
+                        .map(|e| if e {OptionEvent::LocalEnabled(option)}
+                            else {OptionEvent::LocalDisabled(option)}))
+            },
+            _ => (None, None)
+        }
+    }
+
+    // Requests that we start performing an option. Returns the command to
+    // send (if any).
+    pub fn enable_local(&mut self, option: u8) -> Option<Vec<u8>> {
+        let entry = self.entry(option);
+        entry.local_supported = true;
+        request_enable(&mut entry.local).map(|_| vec![IAC, WILL, option])
+    }
+
+    // Requests that we stop performing an option.
+    pub fn disable_local(&mut self, option: u8) -> Option<Vec<u8>> {
+        request_disable(&mut self.entry(option).local)
+            .map(|_| vec![IAC, WONT, option])
+    }
+
+    // Requests that the other side start performing an option.
+    pub fn enable_remote(&mut self, option: u8) -> Option<Vec<u8>> {
+        let entry = self.entry(option);
+        entry.remote_supported = true;
+        request_enable(&mut entry.remote).map(|_| vec![IAC, DO, option])
+    }
+
+    // Requests that the other side stop performing an option.
+    pub fn disable_remote(&mut self, option: u8) -> Option<Vec<u8>> {
+        request_disable(&mut self.entry(option).remote)
+            .map(|_| vec![IAC, DONT, option])
+    }
+
+    fn entry(&mut self, option: u8) -> &mut OptionEntry {
+        self.options.entry(option).or_insert_with(OptionEntry::new)
+    }
+}
+
+// Helper function to handle a WILL (or DO) for one side of an option. Returns
+// the command to reply with (if any) and whether the option ended up enabled
+// or disabled (if it changed).
+fn receive_enable(state: &mut QState, supported: bool, agree: u8, refuse: u8) ->
+    (Option<u8>, Option<bool>)
+{
+    match *state {
+        QState::No => {
+            if supported {
+                *state = QState::Yes;
+                (Some(agree), Some(true))
+            } else {
+                (Some(refuse), None)
+            }
+        },
+        QState::Yes => (None, None),
+        QState::WantNo => {
+            warn!("Telnet option refusal answered by an agreement");
+            *state = QState::No;
+            (None, Some(false))
+        },
+        QState::WantNoOpposite => {
+            warn!("Telnet option refusal answered by an agreement");
+            *state = QState::Yes;
+            (None, Some(true))
+        },
+        QState::WantYes => {
+            *state = QState::Yes;
+            (None, Some(true))
+        },
+        QState::WantYesOpposite => {
+            // We've changed our minds since asking, so the option is turned
+            // straight back off without being reported as enabled.
+            *state = QState::WantNo;
+            (Some(refuse), None)
+        }
+    }
+}
+
+// Helper function to handle a WONT (or DONT) for one side of an option.
+// Returns the command to reply with (if any) and Some(false) if the option
+// ended up disabled.
+fn receive_disable(state: &mut QState, agree: u8, refuse: u8) ->
+    (Option<u8>, Option<bool>)
+{
+    match *state {
+        QState::No => (None, None),
+        QState::Yes => {
+            *state = QState::No;
+            (Some(refuse), Some(false))
+        },
+        QState::WantNo => {
+            *state = QState::No;
+            (None, Some(false))
+        },
+        QState::WantNoOpposite => {
+            *state = QState::WantYes;
+            (Some(agree), Some(false))
+        },
+        QState::WantYes | QState::WantYesOpposite => {
+            *state = QState::No;
+            (None, None)
+        }
+    }
+}
+
+// Helper function to decide whether a change to one side of an option should
+// be reported, given whether it was last reported as enabled.
+fn report(reported: &mut bool, changed: Option<bool>) -> Option<bool> {
+    match changed {
+        Some(enabled) if enabled != *reported => {
+            *reported = enabled;
+            Some(enabled)
+        },
+        _ => None
+    }
+}
+
+// Helper function to start enabling one side of an option. Returns Some if a
+// request needs to be sent.
+fn request_enable(state: &mut QState) -> Option<()> {
+    match *state {
+        QState::No => {
+            *state = QState::WantYes;
+            Some(())
+        },
+        QState::WantNo => {
+            *state = QState::WantNoOpposite;
+            None
+        },
+        QState::WantYesOpposite => {
+            *state = QState::WantYes;
+            None
+        },
+        // Already enabled or already queued.
+        QState::Yes | QState::WantNoOpposite | QState::WantYes => None
+    }
+}
+
+// Helper function to start disabling one side of an option. Returns Some if a
+// request needs to be sent.
+fn request_disable(state: &mut QState) -> Option<()> {
+    match *state {
+        QState::Yes => {
+            *state = QState::WantNo;
+            Some(())
+        },
+        QState::WantNoOpposite => {
+            *state = QState::WantNo;
+            None
+        },
+        QState::WantYes => {
+            *state = QState::WantYesOpposite;
+            None
+        },
+        // Already disabled or already queued.
+        QState::No | QState::WantNo | QState::WantYesOpposite => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{WILL, WONT, DO, DONT, IAC};
+
+    const OPT: u8 = 42;
+
+    fn table() -> OptionTable {
+        let mut options = OptionTable::new();
+        options.support_local(OPT);
+        options.support_remote(OPT);
+        options
+    }
+
+    fn cmd(command: u8) -> Option<Vec<u8>> {
+        Some(vec![IAC, command, OPT])
+    }
+
+    #[test]
+    fn unsupported_options_are_refused() {
+        let mut options = OptionTable::new();
+        assert_eq!(options.receive(WILL, OPT), (cmd(DONT), None));
+        assert_eq!(options.receive(DO, OPT), (cmd(WONT), None));
+        assert!(!options.is_remote_enabled(OPT));
+        assert!(!options.is_local_enabled(OPT));
+        // Being told to stop something that isn't happening needs no answer.
+        assert_eq!(options.receive(WONT, OPT), (None, None));
+        assert_eq!(options.receive(DONT, OPT), (None, None));
+    }
+
+    #[test]
+    fn supported_options_are_agreed_to_once() {
+        let mut options = table();
+        assert_eq!(options.receive(WILL, OPT),
+            (cmd(DO), Some(OptionEvent::RemoteEnabled(OPT))));
+        assert!(options.is_remote_enabled(OPT));
+        // A repeated WILL isn't answered, which would start a loop.
+        assert_eq!(options.receive(WILL, OPT), (None, None));
+
+        assert_eq!(options.receive(DO, OPT),
+            (cmd(WILL), Some(OptionEvent::LocalEnabled(OPT))));
+        assert_eq!(options.receive(DO, OPT), (None, None));
+
+        assert_eq!(options.receive(WONT, OPT),
+            (cmd(DONT), Some(OptionEvent::RemoteDisabled(OPT))));
+        assert_eq!(options.receive(WONT, OPT), (None, None));
+        assert_eq!(options.receive(DONT, OPT),
+            (cmd(WONT), Some(OptionEvent::LocalDisabled(OPT))));
+        assert!(!options.is_remote_enabled(OPT));
+        assert!(!options.is_local_enabled(OPT));
+    }
+
+    #[test]
+    fn requests_are_answered_without_replies() {
+        // WantYes: the agreement isn't answered again.
+        let mut options = OptionTable::new();
+        assert_eq!(options.enable_remote(OPT), cmd(DO));
+        assert_eq!(options.enable_remote(OPT), None);
+        assert_eq!(options.receive(WILL, OPT),
+            (None, Some(OptionEvent::RemoteEnabled(OPT))));
+
+        // WantNo: likewise for the refusal.
+        assert_eq!(options.disable_remote(OPT), cmd(DONT));
+        assert_eq!(options.disable_remote(OPT), None);
+        assert_eq!(options.receive(WONT, OPT),
+            (None, Some(OptionEvent::RemoteDisabled(OPT))));
+
+        // WantYes refused.
+        assert_eq!(options.enable_local(OPT), cmd(WILL));
+        assert_eq!(options.receive(DONT, OPT), (None, None));
+        assert!(!options.is_local_enabled(OPT));
+    }
+
+    #[test]
+    fn want_no_opposite_asks_again_once_disabled() {
+        let mut options = table();
+        options.receive(WILL, OPT);
+        assert_eq!(options.disable_remote(OPT), cmd(DONT));
+        // Queued behind the request in flight.
+        assert_eq!(options.enable_remote(OPT), None);
+        assert_eq!(options.receive(WONT, OPT),
+            (cmd(DO), Some(OptionEvent::RemoteDisabled(OPT))));
+        assert_eq!(options.receive(WILL, OPT),
+            (None, Some(OptionEvent::RemoteEnabled(OPT))));
+        assert!(options.is_remote_enabled(OPT));
+    }
+
+    #[test]
+    fn want_yes_opposite_is_not_reported_as_enabled() {
+        let mut options = table();
+        assert_eq!(options.enable_remote(OPT), cmd(DO));
+        // Changing our minds is queued behind the request in flight.
+        assert_eq!(options.disable_remote(OPT), None);
+        assert_eq!(options.receive(WILL, OPT), (cmd(DONT), None));
+        assert!(!options.is_remote_enabled(OPT));
+        assert_eq!(options.receive(WONT, OPT), (None, None));
+        assert!(!options.is_remote_enabled(OPT));
+
+        // The same for our side.
+        assert_eq!(options.enable_local(OPT), cmd(WILL));
+        assert_eq!(options.disable_local(OPT), None);
+        assert_eq!(options.receive(DO, OPT), (cmd(WONT), None));
+        assert_eq!(options.receive(DONT, OPT), (None, None));
+        assert!(!options.is_local_enabled(OPT));
+    }
+
+    #[test]
+    fn refusal_answered_by_agreement_ends_disabled() {
+        let mut options = table();
+        options.receive(WILL, OPT);
+        assert_eq!(options.disable_remote(OPT), cmd(DONT));
+        assert_eq!(options.receive(WILL, OPT),
+            (None, Some(OptionEvent::RemoteDisabled(OPT))));
+        assert!(!options.is_remote_enabled(OPT));
+    }
+}
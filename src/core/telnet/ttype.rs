@@ -0,0 +1,55 @@
+use super::{subnegotiation, TTYPE};
+
+// Subnegotiation commands.
+pub const IS: u8 = 0;
+pub const SEND: u8 = 1;
+
+// MTTS capability bits, as reported in the third terminal type.
+pub const MTTS_ANSI: u32 = 1;
+pub const MTTS_VT100: u32 = 2;
+pub const MTTS_UTF8: u32 = 4;
+pub const MTTS_256_COLORS: u32 = 8;
+pub const MTTS_MOUSE_TRACKING: u32 = 16;
+pub const MTTS_OSC_COLOR_PALETTE: u32 = 32;
+pub const MTTS_SCREEN_READER: u32 = 64;
+pub const MTTS_PROXY: u32 = 128;
+pub const MTTS_TRUECOLOR: u32 = 256;
+pub const MTTS_MNES: u32 = 512;
+pub const MTTS_MSLP: u32 = 1024;
+pub const MTTS_SSL: u32 = 2048;
+
+// Cycles through the terminal types reported to the server, as described by
+// the MTTS spec: client name, terminal type, then the MTTS capabilities. The
+// last one is repeated once to signal the end of the list before cycling
+// back to the start.
+#[derive(Debug)]
+pub struct TerminalTypes {
+    names: Vec<String>,
+    next_index: usize
+}
+
+impl TerminalTypes {
+    pub fn new(client_name: &str, terminal_type: &str, mtts: u32) ->
+        TerminalTypes
+    {
+        TerminalTypes {
+            names: vec![client_name.to_uppercase(), terminal_type.to_uppercase(),
+                format!("MTTS {}", mtts)],
+            next_index: 0
+        }
+    }
+    pub fn reset(&mut self) {
+        self.next_index = 0;
+    }
+    // Returns the TTYPE IS subnegotiation for the next terminal type.
+    pub fn next(&mut self) -> Vec<u8> {
+        let last = self.names.len() - 1;
+        let name = &self.names[if self.next_index > last {last}
+            else {self.next_index}];
+        self.next_index = (self.next_index + 1) % (self.names.len() + 1);
+
+        let mut data = vec![IS];
+        data.extend(name.bytes());
+        subnegotiation(TTYPE, &data)
+    }
+}
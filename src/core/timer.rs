@@ -0,0 +1,177 @@
+use std::time::{Duration, Instant};
+
+// Something to do (e.g. a script procedure) after a delay, and optionally
+// again every time the delay passes.
+pub struct Timer<T> {
+    pub name: String,
+    pub interval: Duration,
+    pub repeating: bool,
+    // When the timer next goes off.
+    pub due: Instant,
+    pub callback: T
+}
+
+pub struct TimerSet<T> {
+    // Kept in the order they were added.
+    timers: Vec<Timer<T>>
+}
+
+impl<T: Clone> TimerSet<T> {
+    pub fn new() -> TimerSet<T> {
+        TimerSet { timers: Vec::new() }
+    }
+    // Adds a timer, replacing any existing timer with the same name.
+    pub fn add(&mut self, name: &str, interval: Duration, repeating: bool,
+        callback: T)
+    {
+        self.remove(name);
+        self.timers.push(Timer {
+            name: name.to_string(),
+            interval: interval,
+            repeating: repeating,
+            due: Instant::now() + interval,
+            callback: callback
+        });
+    }
+    // Removes a timer, returning whether it existed.
+    pub fn remove(&mut self, name: &str) -> bool {
+        let before = self.timers.len();
+        self.timers.retain(|t| t.name != name);
+        self.timers.len() != before
+    }
+    pub fn iter(&self) -> ::std::slice::Iter<Timer<T>> {
+        self.timers.iter()
+    }
+    // Gets how long until the next timer goes off.
+    pub fn time_until_next(&self) -> Option<Duration> {
+        let now = Instant::now();
+        self.timers.iter().map(|t| t.due).min().map(|due| {
+            if due > now {due - now} else {Duration::from_millis(0)}
+        })
+    }
+    // Gets the callbacks for the timers that have gone off, in the order
+    // they went off. Repeating timers are scheduled again and the rest are
+    // removed.
+    // Repeating timers are scheduled again before their callbacks run, so
+    // they keep going even if a callback fails.
+    pub fn take_due(&mut self) -> Vec<T> {
+        self.take_due_at(Instant::now())
+    }
+    // Helper function to take the timers that have gone off as of a given
+    // time.
+    fn take_due_at(&mut self, now: Instant) -> Vec<T> {
+        let mut due: Vec<(Instant, T)> = self.timers.iter()
+            .filter(|t| t.due <= now)
+            .map(|t| (t.due, t.callback.clone()))
+            .collect();
+        due.sort_by_key(|&(d, _)| d);
+
+        self.timers.retain(|t| t.due > now || t.repeating);
+        for t in self.timers.iter_mut().filter(|t| t.due <= now) {
+            // Skip any runs that were missed rather than running them all
+            // at once.
+            t.due = now + t.interval;
+        }
+        due.into_iter().map(|(_, callback)| callback).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    // Helper function to make a set of timers all added at the same moment,
+    // which is returned.
+    fn timers(specs: &[(&'static str, u64, bool)]) -> (TimerSet<&'static str>, Instant) {
+        let mut set = TimerSet::new();
+        for &(name, millis, repeating) in specs.iter() {
+            set.add(name, ms(millis), repeating, name);
+        }
+        let start = Instant::now();
+        for t in set.timers.iter_mut() {
+            t.due = start + t.interval;
+        }
+        (set, start)
+    }
+
+    #[test]
+    fn one_shot_timers_go_off_once() {
+        let (mut set, start) = timers(&[("once", 100, false)]);
+        assert!(set.take_due_at(start + ms(99)).is_empty());
+        assert_eq!(set.take_due_at(start + ms(100)), vec!["once"]);
+        assert!(set.take_due_at(start + ms(1000)).is_empty());
+        assert_eq!(set.iter().count(), 0);
+    }
+
+    #[test]
+    fn repeating_timers_are_scheduled_again() {
+        let (mut set, start) = timers(&[("tick", 100, true)]);
+        assert_eq!(set.take_due_at(start + ms(100)), vec!["tick"]);
+        assert!(set.take_due_at(start + ms(150)).is_empty());
+        assert_eq!(set.take_due_at(start + ms(200)), vec!["tick"]);
+
+        // Runs that were missed are skipped rather than made up for.
+        assert_eq!(set.take_due_at(start + ms(1000)), vec!["tick"]);
+        assert!(set.take_due_at(start + ms(1050)).is_empty());
+        assert_eq!(set.iter().next().unwrap().due, start + ms(1100));
+    }
+
+    #[test]
+    fn repeating_timers_are_scheduled_before_their_callbacks_run() {
+        // Whatever the callback does (including failing), the timer has
+        // already been scheduled again by the time it's handed out.
+        let (mut set, start) = timers(&[("tick", 100, true), ("once", 100, false)]);
+        let due = set.take_due_at(start + ms(100));
+        assert_eq!(due.len(), 2);
+        let names: Vec<&str> = set.iter().map(|t| &t.name[..]).collect();
+        assert_eq!(names, vec!["tick"]);
+        assert_eq!(set.iter().next().unwrap().due, start + ms(200));
+    }
+
+    #[test]
+    fn removed_timers_dont_go_off() {
+        let (mut set, start) = timers(&[("a", 100, true), ("b", 100, false)]);
+        assert!(set.remove("a"));
+        assert!(!set.remove("a"));
+        assert_eq!(set.take_due_at(start + ms(100)), vec!["b"]);
+        assert!(!set.remove("b"));
+    }
+
+    #[test]
+    fn due_timers_come_out_in_the_order_they_went_off() {
+        let (mut set, start) = timers(&[("late", 300, false), ("early", 100, false),
+            ("middle", 200, true)]);
+        // The list keeps the order the timers were added in.
+        let names: Vec<&str> = set.iter().map(|t| &t.name[..]).collect();
+        assert_eq!(names, vec!["late", "early", "middle"]);
+        assert_eq!(set.take_due_at(start + ms(300)), vec!["early", "middle", "late"]);
+    }
+
+    #[test]
+    fn adding_a_name_again_replaces_the_timer() {
+        let mut set = TimerSet::new();
+        set.add("t", ms(100), false, 1);
+        set.add("u", ms(100), false, 2);
+        set.add("t", ms(500), true, 3);
+        let timers: Vec<(&str, i32, bool)> = set.iter()
+            .map(|t| (&t.name[..], t.callback, t.repeating)).collect();
+        assert_eq!(timers, vec![("u", 2, false), ("t", 3, true)]);
+    }
+
+    #[test]
+    fn time_until_next_timer() {
+        let mut set = TimerSet::new();
+        assert_eq!(set.time_until_next(), None);
+        set.add("soon", ms(0), false, ());
+        assert_eq!(set.time_until_next(), Some(ms(0)));
+        set.add("later", ms(60000), false, ());
+        assert!(set.take_due().len() == 1);
+        let next = set.time_until_next().unwrap();
+        assert!(next > ms(59000) && next <= ms(60000));
+    }
+}
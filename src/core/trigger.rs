@@ -0,0 +1,179 @@
+use regex::Regex;
+
+// A pattern to look for in lines received from the server, along with
+// something to do when it matches (e.g. a script procedure).
+pub struct Trigger<T> {
+    pub name: String,
+    pub regex: Regex,
+    // Triggers with higher priorities are checked first.
+    pub priority: i64,
+    pub enabled: bool,
+    // Triggers can be enabled and disabled together by group.
+    pub group: String,
+    pub callback: T
+}
+
+// A match of a trigger against a line: the trigger's callback, and the text
+// of the whole match followed by each capture group (None for groups that
+// didn't participate).
+pub type TriggerMatch<'a, T> = (&'a T, Vec<Option<String>>);
+
+pub struct TriggerSet<T> {
+    // Kept sorted by priority, highest first. Triggers with the same
+    // priority stay in the order they were added.
+    triggers: Vec<Trigger<T>>
+}
+
+impl<T> TriggerSet<T> {
+    pub fn new() -> TriggerSet<T> {
+        TriggerSet { triggers: Vec::new() }
+    }
+    // Adds a trigger, replacing any existing trigger with the same name.
+    pub fn add(&mut self, name: &str, pattern: &str, priority: i64, group: &str,
+        callback: T) -> Result<(), String>
+    {
+        let regex = match Regex::new(pattern) {
+            Ok(r) => r,
+            Err(e) => return Err(format!("Bad trigger pattern \"{}\": {}",
+                pattern, e))
+        };
+        self.remove(name);
+        let index = self.triggers.iter()
+            .position(|t| t.priority < priority)
+            .unwrap_or(self.triggers.len());
+        self.triggers.insert(index, Trigger {
+            name: name.to_string(),
+            regex: regex,
+            priority: priority,
+            enabled: true,
+            group: group.to_string(),
+            callback: callback
+        });
+        Ok(())
+    }
+    // Removes a trigger, returning whether it existed.
+    pub fn remove(&mut self, name: &str) -> bool {
+        let before = self.triggers.len();
+        self.triggers.retain(|t| t.name != name);
+        self.triggers.len() != before
+    }
+    // Enables or disables a trigger, returning whether it exists.
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) -> bool {
+        match self.triggers.iter_mut().find(|t| t.name == name) {
+            Some(t) => {
+                t.enabled = enabled;
+                true
+            },
+            None => false
+        }
+    }
+    // Enables or disables every trigger in a group, returning how many there
+    // are.
+    pub fn set_group_enabled(&mut self, group: &str, enabled: bool) -> usize {
+        let mut count = 0;
+        for t in self.triggers.iter_mut().filter(|t| t.group == group) {
+            t.enabled = enabled;
+            count += 1;
+        }
+        count
+    }
+    pub fn get(&self, name: &str) -> Option<&Trigger<T>> {
+        self.triggers.iter().find(|t| t.name == name)
+    }
+    // Checks a line against every enabled trigger, in priority order.
+    pub fn matches(&self, line: &str) -> Vec<TriggerMatch<T>> {
+        let mut matches = Vec::new();
+        for t in self.triggers.iter().filter(|t| t.enabled) {
+            if let Some(caps) = t.regex.captures(line) {
+                let groups = (0..caps.len())
+                    .map(|i| caps.at(i).map(|s| s.to_string()))
+                    .collect();
+                matches.push((&t.callback, groups));
+            }
+        }
+        matches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Helper function to get the callbacks of the triggers matching a line.
+    fn matching(triggers: &TriggerSet<&'static str>, line: &str) -> Vec<&'static str> {
+        triggers.matches(line).into_iter().map(|(&callback, _)| callback).collect()
+    }
+
+    #[test]
+    fn higher_priorities_match_first() {
+        let mut triggers = TriggerSet::new();
+        triggers.add("low", "a", -5, "", "low").unwrap();
+        triggers.add("high", "a", 10, "", "high").unwrap();
+        triggers.add("default", "a", 0, "", "default").unwrap();
+        triggers.add("other", "b", 20, "", "other").unwrap();
+        assert_eq!(matching(&triggers, "a"), vec!["high", "default", "low"]);
+        assert_eq!(matching(&triggers, "ab"),
+            vec!["other", "high", "default", "low"]);
+        assert!(matching(&triggers, "c").is_empty());
+    }
+
+    #[test]
+    fn equal_priorities_keep_the_order_added() {
+        let mut triggers = TriggerSet::new();
+        triggers.add("first", "x", 1, "", "first").unwrap();
+        triggers.add("second", "x", 1, "", "second").unwrap();
+        triggers.add("before", "x", 2, "", "before").unwrap();
+        triggers.add("third", "x", 1, "", "third").unwrap();
+        triggers.add("after", "x", 0, "", "after").unwrap();
+        assert_eq!(matching(&triggers, "x"),
+            vec!["before", "first", "second", "third", "after"]);
+    }
+
+    #[test]
+    fn adding_a_name_again_replaces_the_trigger() {
+        let mut triggers = TriggerSet::new();
+        triggers.add("t", "old", 0, "", "old").unwrap();
+        triggers.add("u", "new", 1, "", "u").unwrap();
+        triggers.add("t", "new", 2, "g", "new").unwrap();
+        assert!(matching(&triggers, "old").is_empty());
+        assert_eq!(matching(&triggers, "new"), vec!["new", "u"]);
+        assert_eq!(triggers.get("t").map(|t| t.priority), Some(2));
+
+        // A bad pattern leaves the existing trigger alone.
+        assert!(triggers.add("t", "(", 0, "", "bad").is_err());
+        assert_eq!(matching(&triggers, "new"), vec!["new", "u"]);
+
+        assert!(triggers.remove("t"));
+        assert!(!triggers.remove("t"));
+        assert_eq!(matching(&triggers, "new"), vec!["u"]);
+    }
+
+    #[test]
+    fn enabling_triggers_and_groups() {
+        let mut triggers = TriggerSet::new();
+        triggers.add("a", "z", 0, "combat", "a").unwrap();
+        triggers.add("b", "z", 0, "combat", "b").unwrap();
+        triggers.add("c", "z", 0, "", "c").unwrap();
+
+        assert_eq!(triggers.set_group_enabled("combat", false), 2);
+        assert_eq!(matching(&triggers, "z"), vec!["c"]);
+        assert!(triggers.set_enabled("b", true));
+        assert_eq!(matching(&triggers, "z"), vec!["b", "c"]);
+        assert_eq!(triggers.set_group_enabled("combat", true), 2);
+        assert_eq!(matching(&triggers, "z"), vec!["a", "b", "c"]);
+
+        assert_eq!(triggers.set_group_enabled("missing", false), 0);
+        assert!(!triggers.set_enabled("missing", false));
+        assert!(triggers.get("a").unwrap().enabled);
+    }
+
+    #[test]
+    fn captures_include_unmatched_groups() {
+        let mut triggers = TriggerSet::new();
+        triggers.add("t", r"^(\w+) (hits|misses)( you)?", 0, "", "t").unwrap();
+        let matches = triggers.matches("\u{e9}lan hits the wall");
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].1, vec![Some("\u{e9}lan hits".to_string()),
+            Some("\u{e9}lan".to_string()), Some("hits".to_string()), None]);
+    }
+}
@@ -14,20 +14,27 @@ mod scripting;
 mod session;
 mod ui;
 
-use argparse::{ArgumentParser, Store};
+use argparse::{ArgumentParser, Store, StoreTrue};
 use mio::*;
 use mio::tcp::TcpStream;
 use std::cmp;
-use std::io::Read;
-use std::net::{SocketAddr};
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::{self, SocketAddr};
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
 
 use context::Context;
 use indexed::Indexed;
 use session::Session;
 use ui::UserInterface;
-use tome::{formatted_string, Color, RingBuffer};
+use tome::{formatted_string, Color, ParseState, RingBuffer};
+use tome::encoding::Encoding;
+use tome::telnet::{self, mssp, OptionTable};
+
+// How long to wait for a server to send its MSSP status.
+const MSSP_TIMEOUT_SECS: u64 = 10;
 
 fn main() {
     // Enable logging.
@@ -36,6 +43,9 @@ fn main() {
     // Parse arguments.
     let mut host = "127.0.0.1".to_string();
     let mut port = "4000".to_string();
+    let mut fallback_encoding = "latin1".to_string();
+    let mut mssp_query = false;
+    let mut json = false;
     {
         // test: 66.228.38.196 8679
         let mut ap = ArgumentParser::new();
@@ -44,9 +54,26 @@ fn main() {
             .add_argument("host", Store, "Server IP address");
         ap.refer(&mut port)
             .add_argument("port", Store, "Port number");
+        ap.refer(&mut fallback_encoding)
+            .add_option(&["-e", "--fallback-encoding"], Store,
+            "Encoding for text that isn't UTF-8 (latin1 or cp437)");
+        ap.refer(&mut mssp_query)
+            .add_option(&["--mssp"], StoreTrue,
+            "Print the server's MSSP status and exit");
+        ap.refer(&mut json)
+            .add_option(&["--json"], StoreTrue,
+            "Print the MSSP status as JSON");
         ap.parse_args_or_exit();
     }
 
+    let fallback_encoding = match Encoding::from_name(&fallback_encoding) {
+        Some(e) => e,
+        None => {
+            println!("Error: unknown encoding: {}", &fallback_encoding);
+            return;
+        }
+    };
+
     // Set up polling.
     let poll = Poll::new().unwrap();
 
@@ -63,6 +90,18 @@ fn main() {
             return;
         }
     };
+
+    // Just check the server's status if asked.
+    if mssp_query {
+        match query_mssp(&addr) {
+            Ok(ref variables) if json =>
+                println!("{}", mssp::to_json(variables).pretty()),
+            Ok(ref variables) => print_mssp_table(variables),
+            Err(e) => println!("Error: {}", e)
+        }
+        return;
+    }
+
     let stream = TcpStream::connect(&addr).unwrap();
     poll.register(&stream, Token(1), Ready::readable(), PollOpt::level()).unwrap();
 
@@ -85,17 +124,25 @@ fn main() {
             move |buf| {
                 cmp::max(buf.len(), viewport_lines) - viewport_lines
             })));
+    context.current_session_mut().decoder.set_fallback(fallback_encoding);
+
+    // Let the server know the window size.
+    actions::set_window_size(&mut context, ui.output_win_width(),
+        viewport_lines);
 
     // Load the config file.
     actions::reload_config(&mut context);
 
     // Display the initial UI state.
-    update_ui(&mut ui, &context);
+    update_ui(&mut ui, &mut context);
     
     // Run the polling loop.
     let mut events = Events::with_capacity(1024);
     'main: loop {
-        match poll.poll(&mut events, None) {
+        // Wake up in time to flush any partial line as a prompt and to run
+        // any timers.
+        let timeout = actions::poll_timeout(&context);
+        match poll.poll(&mut events, timeout) {
             Err(e) => {
                 match e.kind() {
                     std::io::ErrorKind::Interrupted => {
@@ -104,15 +151,8 @@ fn main() {
 
                         // Resize.
                         ui.restart();
-                        let viewport_lines = ui.output_win_height();
-                        context.viewport_lines = viewport_lines;
-                        for session in context.sessions.iter_mut() {
-                            session.scrollback_buf.set_limit(
-                                move |buf| {
-                                    cmp::max(buf.len(), viewport_lines) - viewport_lines
-                                });
-                        }
-                        update_ui(&mut ui, &context);
+                        fit_viewport(&ui, &mut context);
+                        update_ui(&mut ui, &mut context);
                         for session in context.sessions.iter_mut() {
                             poll.reregister(&session.connection, Token(1), Ready::readable(), PollOpt::edge())
                                 .unwrap();
@@ -123,6 +163,10 @@ fn main() {
             }
             _ => ()
         }
+        let flushed = actions::flush_partial_line(&mut context);
+        if actions::run_timers(&mut context) || flushed {
+            update_ui(&mut ui, &mut context);
+        }
         for event in events.iter() {
             match event.token() {
                 Token(0) => {
@@ -160,7 +204,7 @@ fn main() {
                         match keep_going {
                             Some(kp) => {
                                 if kp {
-                                    update_ui(&mut ui, &context);
+                                    update_ui(&mut ui, &mut context);
                                 } else {
                                     // Stop polling.
                                     break 'main;
@@ -172,7 +216,7 @@ fn main() {
                                     formatted_string::with_color(
                                         &format!("No binding found for keycode: {:?}\n",
                                         keycode), Color::Red));
-                                update_ui(&mut ui, &context);
+                                update_ui(&mut ui, &mut context);
                             }
                         }
                     }
@@ -185,13 +229,13 @@ fn main() {
                         Ok(a) =>  {
                             if a > 0 {
                                 actions::receive_data(&mut context, &buffer[0..a]);
-                                update_ui(&mut ui, &context);
+                                update_ui(&mut ui, &mut context);
                             } else {
                                 // Reading 0 bytes indicates the connection was closed.
                                 poll.deregister(&context.current_session().connection);
                             }
 
-                            update_ui(&mut ui, &context);
+                            update_ui(&mut ui, &mut context);
                         },
                         Err(e) => {
                             match e.kind() {
@@ -210,17 +254,40 @@ fn main() {
     ui.teardown();
 }
 
-fn update_ui(ui: &mut UserInterface, context: &Context) {
+fn update_ui(ui: &mut UserInterface, context: &mut Context) {
+    // The prompt line appears with the first prompt, shrinking the output.
+    let has_prompt = context.current_session().prompt.is_some();
+    if ui.show_prompt(has_prompt) {
+        fit_viewport(ui, context);
+    }
+
     let scroll_index = context.current_session().scrollback_buf.index();
     let history_index = context.history.index();
     let output_win_height = ui.output_win_height();
     ui.update(
         context.current_session().scrollback_buf.data
             .most_recent(scroll_index + output_win_height),
+        context.current_session().prompt.as_ref(),
         context.history.data.most_recent(history_index + 1),
+        actions::is_echo_off(context),
         context.cursor_index);
 }
 
+// Helper function to fit the scrolling and the window size sent to the server
+// to the output window, after it changes size.
+fn fit_viewport(ui: &UserInterface, context: &mut Context) {
+    let viewport_lines = ui.output_win_height();
+    context.viewport_lines = viewport_lines;
+    for session in context.sessions.iter_mut() {
+        session.scrollback_buf.set_limit(
+            move |buf| {
+                cmp::max(buf.len(), viewport_lines) - viewport_lines
+            });
+    }
+    let viewport_width = ui.output_win_width();
+    actions::set_window_size(context, viewport_width, viewport_lines);
+}
+
 // Helper function to read the config filepath.
 fn get_config_filepath() -> Result<PathBuf, String> {
     let xdg_dirs = match xdg::BaseDirectories::with_prefix("tome") {
@@ -232,3 +299,56 @@ fn get_config_filepath() -> Result<PathBuf, String> {
         None => Err("Could not find config file".to_string())
     }
 }
+
+// Helper function to connect to a server just long enough to collect its
+// MSSP status.
+fn query_mssp(addr: &SocketAddr) -> Result<BTreeMap<String, Vec<String>>, String> {
+    let mut stream = match net::TcpStream::connect(addr) {
+        Ok(s) => s,
+        Err(e) => return Err(format!("Failed to connect: {}", e))
+    };
+    if let Err(e) = stream.set_read_timeout(
+        Some(Duration::from_secs(MSSP_TIMEOUT_SECS)))
+    {
+        return Err(format!("{}", e));
+    }
+
+    // Refuse every option except MSSP.
+    let mut options = OptionTable::new();
+    options.support_remote(telnet::MSSP);
+
+    let mut telnet_state = ParseState::NotInProgress;
+    let mut buffer = [0; 4096];
+    loop {
+        let num = match stream.read(&mut buffer) {
+            Ok(0) => return Err("Connection closed before MSSP data was received"
+                .to_string()),
+            Ok(n) => n,
+            Err(e) => return Err(format!("No MSSP data received: {}", e))
+        };
+        for byte in buffer[0..num].iter() {
+            telnet_state = telnet::parse(&telnet_state, *byte);
+            if let ParseState::Success(ref cmd) = telnet_state {
+                if cmd.len() == 3 {
+                    if let (Some(reply), _) = options.receive(cmd[1], cmd[2]) {
+                        if let Err(e) = stream.write_all(&reply) {
+                            return Err(format!("Couldn't answer the server: {}", e));
+                        }
+                    }
+                } else if let Some((telnet::MSSP, data)) =
+                    telnet::parse_subnegotiation(cmd)
+                {
+                    return mssp::parse(&data);
+                }
+            }
+        }
+    }
+}
+
+// Helper function to print MSSP variables as a table.
+fn print_mssp_table(variables: &BTreeMap<String, Vec<String>>) {
+    let width = variables.keys().map(|k| k.len()).max().unwrap_or(0);
+    for (name, values) in variables.iter() {
+        println!("{:width$}  {}", name, values.join(", "), width = width);
+    }
+}
@@ -1,7 +1,14 @@
 mod resin_interface;
 
-use tome::formatted_string::FormattedString;
+use regex::Regex;
+use std::hash::{Hash, Hasher};
+use tome::formatted_string::{Format, FormattedString};
+use tome::msp::Trigger;
+use tome::mxp::Link;
+use tome::telnet::gmcp::Json;
+use tome::telnet::msdp::MsdpValue;
 use self::resin_interface::ResinScriptInterface;
+use std::time::Duration;
 
 #[derive(PartialEq, Eq, Clone, Hash)]
 pub enum ScriptAction {
@@ -9,7 +16,56 @@ pub enum ScriptAction {
     WriteScrollback(FormattedString),
     SendInput(String),
     Reconnect,
-    SearchBackwards(String)
+    SearchBackwards(String),
+    SendGmcp(String, String),
+    SendMsdp(String, Vec<String>),
+    RunProgram(String, Vec<String>),
+    // Changes to the line being received: drop it, give the text matching a
+    // regex a new format, or replace the text matching a regex.
+    GagLine,
+    HighlightLine(LinePattern, Format),
+    SubstituteLine(LinePattern, FormattedString)
+}
+
+impl ScriptAction {
+    pub fn is_line_edit(&self) -> bool {
+        match *self {
+            ScriptAction::GagLine |
+            ScriptAction::HighlightLine(..) |
+            ScriptAction::SubstituteLine(..) => true,
+            _ => false
+        }
+    }
+}
+
+// A regex for a line edit, compiled once when the action is made rather than
+// for every line it's applied to. Patterns are compared by their text.
+#[derive(Clone)]
+pub struct LinePattern(pub Regex);
+
+impl PartialEq for LinePattern {
+    fn eq(&self, other: &LinePattern) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Eq for LinePattern {}
+
+impl Hash for LinePattern {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.as_str().hash(state);
+    }
+}
+
+// A key binding made by a script.
+#[derive(PartialEq, Eq, Clone)]
+pub enum KeyBinding {
+    // A built-in action, by name (e.g. "history-prev").
+    Action(String),
+    // A script procedure, called through run_key_binding().
+    Script,
+    // The key was unbound, removing any default binding.
+    Unbound
 }
 
 pub trait ScriptInterface {
@@ -17,6 +73,33 @@ pub trait ScriptInterface {
         Result<Vec<ScriptAction>, String>;
     fn recv_hook(&mut self, data: &FormattedString) ->
         Result<Vec<ScriptAction>, String>;
+    fn prompt_hook(&mut self, prompt: &FormattedString) ->
+        Result<Vec<ScriptAction>, String>;
+    fn gmcp_hook(&mut self, package: &str, data: &Json) ->
+        Result<Vec<ScriptAction>, String>;
+    fn msdp_hook(&mut self, variable: &str, value: &MsdpValue) ->
+        Result<Vec<ScriptAction>, String>;
+    fn msp_hook(&mut self, trigger: &Trigger) ->
+        Result<Vec<ScriptAction>, String>;
+    // Runs the triggers matching a line received from the server. Every
+    // matching trigger runs even if an earlier one fails, so this gives the
+    // actions from the ones that succeeded along with the errors.
+    fn run_triggers(&mut self, line: &str) -> (Vec<ScriptAction>, Vec<String>);
+    // Runs the timers that have gone off. As with triggers, every timer runs
+    // even if an earlier one fails.
+    fn run_timers(&mut self) -> (Vec<ScriptAction>, Vec<String>);
+    // Gets how long until the next timer goes off, if there are any.
+    fn time_until_next_timer(&self) -> Option<Duration>;
+    // Gets the key bindings made by scripts, by key name.
+    fn key_bindings(&self) -> Vec<(String, KeyBinding)>;
+    // Runs the script procedure bound to a key.
+    fn run_key_binding(&mut self, key_name: &str) ->
+        Result<Vec<ScriptAction>, String>;
+    fn set_msdp_variable(&mut self, variable: &str, value: &MsdpValue);
+    // Sets the MXP links in the line being received, along with the text of
+    // each, for tome:line-links.
+    fn set_line_links(&mut self, links: Vec<(String, Link)>);
+    fn clear_msdp_variables(&mut self);
     fn evaluate(&mut self, s: &str) -> Result<(), String>;
 }
 
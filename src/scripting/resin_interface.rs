@@ -1,15 +1,58 @@
 use super::super::tome::formatted_string::{self, Format, FormattedString};
+use super::super::tome::msp::{Trigger, TriggerKind};
+use super::super::tome::mxp::Link;
+use super::super::tome::telnet::gmcp::Json;
+use super::super::tome::timer::TimerSet;
+use super::super::tome::trigger::TriggerSet;
+use super::super::tome::telnet::msdp::MsdpValue;
+use regex::Regex;
 use resin::{Datum, Interpreter, RuntimeError};
-use scripting::{ScriptAction, ScriptInterface};
+use scripting::{KeyBinding, LinePattern, ScriptAction, ScriptInterface};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+// MSDP commands that scripts can send, along with their function names.
+const MSDP_COMMANDS: [(&'static str, &'static str); 5] = [
+    ("tome:msdp-list", "LIST"),
+    ("tome:msdp-report", "REPORT"),
+    ("tome:msdp-unreport", "UNREPORT"),
+    ("tome:msdp-send", "SEND"),
+    ("tome:msdp-reset", "RESET")
+];
 
 pub struct ResinScriptInterface {
-    interp: Interpreter
+    interp: Interpreter,
+    msdp_variables: Rc<RefCell<HashMap<String, MsdpValue>>>,
+    // Triggers registered by scripts, with the procedures to call on a match.
+    triggers: Rc<RefCell<TriggerSet<Datum>>>,
+    // Timers created by scripts, with the procedures to call when they go off.
+    timers: Rc<RefCell<TimerSet<Datum>>>,
+    // Key bindings made by scripts: a procedure, the name of a built-in
+    // action, or #f if the key was unbound.
+    key_bindings: Rc<RefCell<HashMap<String, Datum>>>,
+    // The MXP links in the line being received.
+    line_links: Rc<RefCell<Vec<(String, Link)>>>
 }
 
 impl ResinScriptInterface {
     pub fn new() -> Self {
         let mut interp = Interpreter::new();
-        interp.with_root(|root| {
+        let msdp_variables = Rc::new(RefCell::new(HashMap::new()));
+        let variables = msdp_variables.clone();
+        let triggers = Rc::new(RefCell::new(TriggerSet::new()));
+        let (add_triggers, remove_triggers, enable_triggers, group_triggers) =
+            (triggers.clone(), triggers.clone(), triggers.clone(), triggers.clone());
+        let timers = Rc::new(RefCell::new(TimerSet::new()));
+        let (add_timers, remove_timers, list_timers) =
+            (timers.clone(), timers.clone(), timers.clone());
+        let key_bindings = Rc::new(RefCell::new(HashMap::new()));
+        let (bind_keys, unbind_keys) = (key_bindings.clone(), key_bindings.clone());
+        let line_links: Rc<RefCell<Vec<(String, Link)>>> =
+            Rc::new(RefCell::new(Vec::new()));
+        let get_line_links = line_links.clone();
+        interp.with_root(move |root| {
             root.define_fn("tome:reload-config", |args: &[Datum]| {
                 expect_args!(args == 0);
                 Ok(Datum::ext(ScriptAction::ReloadConfig, "action:reload-config"))
@@ -40,26 +83,378 @@ impl ResinScriptInterface {
             root.define_fn("tome:make-fstring", |args: &[Datum]| {
                 expect_args!(args == 1);
                 let string = try_unwrap_arg!(args[0] => String);
-                Ok(Datum::ext(formatted_string::from_markup(string),
-                    "formatted-string"))
+                match formatted_string::from_markup(string) {
+                    Ok(fs) => Ok(Datum::ext(fs, "formatted-string")),
+                    Err(e) => Err(RuntimeError { msg: e })
+                }
             });
             root.define_fn("tome:fstring-to-string", |args: &[Datum]| {
                 expect_args!(args == 1);
                 let fs = try_unwrap_arg!(args[0] => FormattedString);
                 Ok(Datum::String(formatted_string::to_string(fs)))
             });
+            root.define_fn("tome:fstring-to-ansi", |args: &[Datum]| {
+                expect_args!(args == 1);
+                let fs = try_unwrap_arg!(args[0] => FormattedString);
+                Ok(Datum::String(formatted_string::to_ansi(fs)))
+            });
+            root.define_fn("tome:fstring-to-html", |args: &[Datum]| {
+                expect_args!(args == 1);
+                let fs = try_unwrap_arg!(args[0] => FormattedString);
+                Ok(Datum::String(formatted_string::to_html(fs)))
+            });
             root.define_fn("tome:search-backwards", |args: &[Datum]| {
                 expect_args!(args == 1);
                 let s = try_unwrap_arg!(args[0] => String);
                 Ok(Datum::ext(ScriptAction::SearchBackwards(s.clone()),
                     "action:search-backwards"))
             });
+            root.define_fn("tome:send-gmcp", |args: &[Datum]| {
+                // The payload is optional and should already be JSON.
+                let payload = match args.len() {
+                    1 => String::new(),
+                    _ => {
+                        expect_args!(args == 2);
+                        try_unwrap_arg!(args[1] => String).clone()
+                    }
+                };
+                let package = try_unwrap_arg!(args[0] => String).clone();
+                Ok(Datum::ext(ScriptAction::SendGmcp(package, payload),
+                    "action:send-gmcp"))
+            });
+            root.define_fn("tome:run-program", |args: &[Datum]| {
+                // Useful for things like playing sounds with an external
+                // player.
+                expect_args!(args >= 1);
+                let program = try_unwrap_arg!(args[0] => String).clone();
+                let mut program_args = Vec::new();
+                for arg in args[1..].iter() {
+                    program_args.push(try_unwrap_arg!(arg => String).clone());
+                }
+                Ok(Datum::ext(ScriptAction::RunProgram(program, program_args),
+                    "action:run-program"))
+            });
+            root.define_fn("tome:gag", |args: &[Datum]| {
+                expect_args!(args == 0);
+                Ok(Datum::ext(ScriptAction::GagLine, "action:gag-line"))
+            });
+            root.define_fn("tome:highlight", |args: &[Datum]| {
+                // The format is given as markup codes, e.g. "{R{*".
+                expect_args!(args == 2);
+                let pattern = try!(compile_pattern(try_unwrap_arg!(args[0] => String)));
+                let codes = try_unwrap_arg!(args[1] => String);
+                match formatted_string::format_from_markup(codes) {
+                    Ok(format) => Ok(Datum::ext(
+                        ScriptAction::HighlightLine(pattern, format),
+                        "action:highlight-line")),
+                    Err(e) => Err(RuntimeError { msg: e })
+                }
+            });
+            root.define_fn("tome:substitute", |args: &[Datum]| {
+                expect_args!(args == 2);
+                let pattern = try!(compile_pattern(try_unwrap_arg!(args[0] => String)));
+                let replacement = match unwrap_arg!(args[1] => FormattedString) {
+                    Ok(f) => f.clone(),
+                    Err(_) => {
+                        let string = try_unwrap_arg!(args[1] => String);
+                        formatted_string::with_format(string, Format::default())
+                    }
+                };
+                Ok(Datum::ext(ScriptAction::SubstituteLine(pattern, replacement),
+                    "action:substitute-line"))
+            });
+            root.define_fn("tome:msdp-get", move |args: &[Datum]| {
+                expect_args!(args == 1);
+                let name = try_unwrap_arg!(args[0] => String);
+                match variables.borrow().get(name) {
+                    Some(v) => Ok(msdp_to_datum(v)),
+                    None => Ok(Datum::Boolean(false))
+                }
+            });
+            root.define_fn("tome:line-links", move |args: &[Datum]| {
+                // Each link is described by an association list.
+                expect_args!(args == 0);
+                Ok(make_list(get_line_links.borrow().iter()
+                    .map(|&(ref text, ref link)| link_to_datum(text, link))
+                    .collect()))
+            });
+            root.define_fn("tome:add-trigger", move |args: &[Datum]| {
+                // The priority and group are optional.
+                expect_args!(args >= 3);
+                let name = try_unwrap_arg!(args[0] => String);
+                let pattern = try_unwrap_arg!(args[1] => String);
+                let priority = match args.len() {
+                    3 => 0,
+                    _ => match args[3] {
+                        Datum::Number(n) => n,
+                        _ => return Err(RuntimeError {
+                            msg: String::from("Trigger priority must be a number")
+                        })
+                    }
+                };
+                let group = match args.len() {
+                    3 | 4 => "",
+                    _ => {
+                        expect_args!(args == 5);
+                        try_unwrap_arg!(args[4] => String)
+                    }
+                };
+                match add_triggers.borrow_mut().add(name, pattern, priority,
+                    group, args[2].clone())
+                {
+                    Ok(_) => Ok(Datum::Boolean(true)),
+                    Err(e) => Err(RuntimeError { msg: e })
+                }
+            });
+            root.define_fn("tome:remove-trigger", move |args: &[Datum]| {
+                expect_args!(args == 1);
+                let name = try_unwrap_arg!(args[0] => String);
+                Ok(Datum::Boolean(remove_triggers.borrow_mut().remove(name)))
+            });
+            root.define_fn("tome:enable-trigger", move |args: &[Datum]| {
+                expect_args!(args == 2);
+                let name = try_unwrap_arg!(args[0] => String);
+                let enabled = match args[1] {
+                    Datum::Boolean(b) => b,
+                    _ => return Err(RuntimeError {
+                        msg: String::from("Whether to enable the trigger must be a boolean")
+                    })
+                };
+                Ok(Datum::Boolean(
+                    enable_triggers.borrow_mut().set_enabled(name, enabled)))
+            });
+            root.define_fn("tome:enable-trigger-group", move |args: &[Datum]| {
+                expect_args!(args == 2);
+                let group = try_unwrap_arg!(args[0] => String);
+                let enabled = match args[1] {
+                    Datum::Boolean(b) => b,
+                    _ => return Err(RuntimeError {
+                        msg: String::from("Whether to enable the group must be a boolean")
+                    })
+                };
+                let count = group_triggers.borrow_mut()
+                    .set_group_enabled(group, enabled);
+                Ok(Datum::Boolean(count > 0))
+            });
+            root.define_fn("tome:add-timer", move |args: &[Datum]| {
+                // Timers go off once unless they're asked to repeat.
+                expect_args!(args >= 3);
+                let name = try_unwrap_arg!(args[0] => String);
+                let millis = match args[1] {
+                    Datum::Number(n) if n >= 0 => n as u64,
+                    _ => return Err(RuntimeError {
+                        msg: String::from("Timer delay must be a number of milliseconds")
+                    })
+                };
+                let repeating = match args.len() {
+                    3 => false,
+                    _ => {
+                        expect_args!(args == 4);
+                        match args[3] {
+                            Datum::Boolean(b) => b,
+                            _ => return Err(RuntimeError {
+                                msg: String::from("Whether the timer repeats must be a boolean")
+                            })
+                        }
+                    }
+                };
+                if repeating && millis == 0 {
+                    return Err(RuntimeError {
+                        msg: String::from("Repeating timers need a delay")
+                    });
+                }
+                add_timers.borrow_mut().add(name, Duration::from_millis(millis),
+                    repeating, args[2].clone());
+                Ok(Datum::Boolean(true))
+            });
+            root.define_fn("tome:remove-timer", move |args: &[Datum]| {
+                expect_args!(args == 1);
+                let name = try_unwrap_arg!(args[0] => String);
+                Ok(Datum::Boolean(remove_timers.borrow_mut().remove(name)))
+            });
+            root.define_fn("tome:list-timers", move |args: &[Datum]| {
+                // Each timer is described by an association list.
+                expect_args!(args == 0);
+                let now = Instant::now();
+                let to_millis = |d: Duration|
+                    (d.as_secs() * 1000 + d.subsec_nanos() as u64 / 1000000) as i64;
+                Ok(make_list(list_timers.borrow().iter().map(|t| {
+                    let remaining = if t.due > now {t.due - now}
+                        else {Duration::from_millis(0)};
+                    let fields = vec![
+                        ("name", Datum::String(t.name.clone())),
+                        ("delay", Datum::Number(to_millis(t.interval))),
+                        ("repeating", Datum::Boolean(t.repeating)),
+                        ("remaining", Datum::Number(to_millis(remaining)))
+                    ];
+                    make_list(fields.into_iter()
+                        .map(|(k, v)| Datum::pair(Datum::String(k.to_string()), v))
+                        .collect())
+                }).collect()))
+            });
+            root.define_fn("tome:bind-key", move |args: &[Datum]| {
+                // The binding is either a procedure or the name of a built-in
+                // action. Bindings take effect once the config is loaded.
+                expect_args!(args == 2);
+                let key_name = try_unwrap_arg!(args[0] => String).clone();
+                match args[1] {
+                    Datum::Procedure(..) | Datum::String(_) => (),
+                    _ => return Err(RuntimeError {
+                        msg: String::from("Keys must be bound to a procedure or an action name")
+                    })
+                }
+                bind_keys.borrow_mut().insert(key_name, args[1].clone());
+                Ok(Datum::Boolean(true))
+            });
+            root.define_fn("tome:unbind-key", move |args: &[Datum]| {
+                expect_args!(args == 1);
+                let key_name = try_unwrap_arg!(args[0] => String).clone();
+                unbind_keys.borrow_mut().insert(key_name, Datum::Boolean(false));
+                Ok(Datum::Boolean(true))
+            });
+            for &(fn_name, command) in MSDP_COMMANDS.iter() {
+                root.define_fn(fn_name, move |args: &[Datum]| {
+                    msdp_command(command, args)
+                });
+            }
         });
 
-        ResinScriptInterface { interp: interp }
+        ResinScriptInterface {
+            interp: interp,
+            msdp_variables: msdp_variables,
+            triggers: triggers,
+            timers: timers,
+            key_bindings: key_bindings,
+            line_links: line_links
+        }
+    }
+
+    // Helper function to evaluate a hook expression that returns a list of
+    // actions.
+    fn evaluate_actions(&mut self, expr: &Datum) ->
+        Result<Vec<ScriptAction>, String>
+    {
+        match self.interp.evaluate_datum(expr) {
+            Ok(d) => {
+                let mut actions = Vec::<ScriptAction>::new();
+                for da in d.as_vec().0.into_iter() {
+                    match unwrap_arg!(da => ScriptAction) {
+                        Ok(a) => actions.push(a),
+                        Err(_) => return Err(String::from("Non-action returned"))
+                    }
+                }
+                Ok(actions)
+            },
+            Err((e, trace)) => {
+                Err(format!("Script error: {}\n{}\n", &e.msg, &trace))
+            }
+        }
+    }
+}
+
+// Helper function to build an MSDP command action. The argument can be either
+// a single string or a list of strings.
+fn msdp_command(command: &str, args: &[Datum]) -> Result<Datum, RuntimeError> {
+    expect_args!(args == 1);
+    let values = match unwrap_arg!(args[0] => String) {
+        Ok(s) => vec![s],
+        Err(_) => {
+            let mut values = Vec::new();
+            for d in args[0].as_vec().0.into_iter() {
+                values.push(try_unwrap_arg!(d => String).clone());
+            }
+            values
+        }
+    };
+    Ok(Datum::ext(ScriptAction::SendMsdp(command.to_string(), values),
+        "action:send-msdp"))
+}
+
+// Helper function to compile the pattern for a line edit, so that mistakes
+// are reported to the script.
+fn compile_pattern(pattern: &str) -> Result<LinePattern, RuntimeError> {
+    match Regex::new(pattern) {
+        Ok(r) => Ok(LinePattern(r)),
+        Err(e) => Err(RuntimeError {
+            msg: format!("Bad pattern \"{}\": {}", pattern, e)
+        })
     }
 }
 
+// Helper function to build a list from a vector of data.
+fn make_list(items: Vec<Datum>) -> Datum {
+    items.into_iter().rev().fold(Datum::EmptyList,
+        |list, item| Datum::pair(item, list))
+}
+
+// Helper function to convert JSON into data usable by scripts. Objects become
+// association lists and arrays become lists. Numbers are integers only, so
+// non-integers are kept as strings rather than truncated, and null becomes the
+// symbol null.
+fn json_to_datum(json: &Json) -> Datum {
+    match *json {
+        Json::I64(n) => Datum::Number(n),
+        Json::U64(n) => Datum::Number(n as i64),
+        Json::F64(n) => Datum::String(n.to_string()),
+        Json::String(ref s) => Datum::String(s.clone()),
+        Json::Boolean(b) => Datum::Boolean(b),
+        Json::Array(ref a) => make_list(a.iter().map(json_to_datum).collect()),
+        Json::Object(ref o) => make_list(o.iter()
+            .map(|(k, v)| Datum::pair(Datum::String(k.clone()), json_to_datum(v)))
+            .collect()),
+        Json::Null => Datum::Symbol(String::from("null"))
+    }
+}
+
+// Helper function to convert an MSDP value into data usable by scripts.
+// Tables become association lists and arrays become lists.
+fn msdp_to_datum(value: &MsdpValue) -> Datum {
+    match *value {
+        MsdpValue::String(ref s) => Datum::String(s.clone()),
+        MsdpValue::Array(ref a) => make_list(a.iter().map(msdp_to_datum).collect()),
+        MsdpValue::Table(ref t) => make_list(t.iter()
+            .map(|&(ref k, ref v)| Datum::pair(Datum::String(k.clone()), msdp_to_datum(v)))
+            .collect())
+    }
+}
+
+// Helper function to convert an MSP trigger into an association list usable
+// by scripts.
+fn trigger_to_datum(trigger: &Trigger) -> Datum {
+    let optional = |s: &Option<String>| match *s {
+        Some(ref s) => Datum::String(s.clone()),
+        None => Datum::Boolean(false)
+    };
+    let fields = vec![
+        ("file", Datum::String(trigger.file.clone())),
+        ("volume", Datum::Number(trigger.volume as i64)),
+        ("loops", Datum::Number(trigger.loops as i64)),
+        ("priority", Datum::Number(trigger.priority as i64)),
+        ("continue", Datum::Boolean(trigger.continue_playing)),
+        ("type", optional(&trigger.trigger_type)),
+        ("url", optional(&trigger.url))
+    ];
+    make_list(fields.into_iter()
+        .map(|(k, v)| Datum::pair(Datum::String(k.to_string()), v))
+        .collect())
+}
+
+// Helper function to convert an MXP link into an association list.
+fn link_to_datum(text: &str, link: &Link) -> Datum {
+    let fields = vec![
+        ("text", Datum::String(text.to_string())),
+        ("href", Datum::String(link.href.clone())),
+        ("hint", match link.hint {
+            Some(ref h) => Datum::String(h.clone()),
+            None => Datum::Boolean(false)
+        }),
+        ("prompt", Datum::Boolean(link.prompt))
+    ];
+    make_list(fields.into_iter()
+        .map(|(k, v)| Datum::pair(Datum::String(k.to_string()), v))
+        .collect())
+}
+
 impl ScriptInterface for ResinScriptInterface {
     fn send_hook(&mut self, input: &str) ->
         Result<Vec<ScriptAction>, String>
@@ -68,22 +463,7 @@ impl ScriptInterface for ResinScriptInterface {
         if let Some(h) = hook {
             // Evaluate the hook with the input.
             let expr = list!(h, Datum::String(String::from(input)));
-            let eval_result = self.interp.evaluate_datum(&expr);
-            match eval_result {
-                Ok(d) => {
-                    let mut actions = Vec::<ScriptAction>::new();
-                    for da in d.as_vec().0.into_iter() {
-                        match unwrap_arg!(da => ScriptAction) {
-                            Ok(a) => actions.push(a),
-                            Err(_) => return Err(String::from("Non-action returned"))
-                        }
-                    }
-                    Ok(actions)
-                },
-                Err((e, trace)) => {
-                    Err(format!("Script error: {}\n{}\n", &e.msg, &trace))
-                }
-            }
+            self.evaluate_actions(&expr)
         } else {
             Ok(vec![ScriptAction::SendInput(String::from(input))])
         }
@@ -95,26 +475,137 @@ impl ScriptInterface for ResinScriptInterface {
         if let Some(h) = hook {
             // Evaluate the hook with the input.
             let expr = list!(h, Datum::ext(data.clone(), "formatted-string"));
-            let eval_result = self.interp.evaluate_datum(&expr);
-            match eval_result {
-                Ok(d) => {
-                    let mut actions = Vec::<ScriptAction>::new();
-                    for da in d.as_vec().0.into_iter() {
-                        match unwrap_arg!(da => ScriptAction) {
-                            Ok(a) => actions.push(a),
-                            Err(_) => return Err(String::from("Non-action returned"))
-                        }
-                    }
-                    Ok(actions)
-                },
-                Err((e, trace)) => {
-                    Err(format!("Script error: {}\n{}\n", &e.msg, &trace))
-                }
-            }
+            self.evaluate_actions(&expr)
         } else {
             Ok(vec![ScriptAction::WriteScrollback(data.clone())])
         }
     }
+    fn prompt_hook(&mut self, prompt: &FormattedString) ->
+        Result<Vec<ScriptAction>, String>
+    {
+        let hook = self.interp.root().get("prompt-hook");
+        if let Some(h) = hook {
+            // Evaluate the hook with the prompt.
+            let expr = list!(h, Datum::ext(prompt.clone(), "formatted-string"));
+            self.evaluate_actions(&expr)
+        } else {
+            // The prompt is already shown on the prompt line.
+            Ok(vec![])
+        }
+    }
+    fn gmcp_hook(&mut self, package: &str, data: &Json) ->
+        Result<Vec<ScriptAction>, String>
+    {
+        let hook = self.interp.root().get("gmcp-hook");
+        if let Some(h) = hook {
+            // Evaluate the hook with the package name and payload.
+            let expr = list!(h, Datum::String(String::from(package)),
+                json_to_datum(data));
+            self.evaluate_actions(&expr)
+        } else {
+            Ok(vec![])
+        }
+    }
+    fn msdp_hook(&mut self, variable: &str, value: &MsdpValue) ->
+        Result<Vec<ScriptAction>, String>
+    {
+        self.set_msdp_variable(variable, value);
+        let hook = self.interp.root().get("msdp-hook");
+        if let Some(h) = hook {
+            // Evaluate the hook with the variable name and value.
+            let expr = list!(h, Datum::String(String::from(variable)),
+                msdp_to_datum(value));
+            self.evaluate_actions(&expr)
+        } else {
+            Ok(vec![])
+        }
+    }
+    fn msp_hook(&mut self, trigger: &Trigger) ->
+        Result<Vec<ScriptAction>, String>
+    {
+        let hook = self.interp.root().get("msp-hook");
+        if let Some(h) = hook {
+            // Evaluate the hook with the kind of trigger and its parameters.
+            let kind = match trigger.kind {
+                TriggerKind::Sound => "sound",
+                TriggerKind::Music => "music"
+            };
+            let expr = list!(h, Datum::String(String::from(kind)),
+                trigger_to_datum(trigger));
+            self.evaluate_actions(&expr)
+        } else {
+            Ok(vec![])
+        }
+    }
+    fn run_triggers(&mut self, line: &str) -> (Vec<ScriptAction>, Vec<String>) {
+        // Find the matches up front, since callbacks can change the triggers.
+        let calls: Vec<Datum> = self.triggers.borrow().matches(line).into_iter()
+            .map(|(callback, groups)| {
+                let captures = make_list(groups.into_iter()
+                    .map(|g| match g {
+                        Some(s) => Datum::String(s),
+                        None => Datum::Boolean(false)
+                    })
+                    .collect());
+                list!(callback.clone(), Datum::String(String::from(line)),
+                    captures)
+            })
+            .collect();
+        let mut actions = Vec::new();
+        let mut errors = Vec::new();
+        for expr in calls.iter() {
+            match self.evaluate_actions(expr) {
+                Ok(a) => actions.extend(a),
+                Err(e) => errors.push(e)
+            }
+        }
+        (actions, errors)
+    }
+    fn run_timers(&mut self) -> (Vec<ScriptAction>, Vec<String>) {
+        // Take the callbacks up front, since they can change the timers.
+        let callbacks = self.timers.borrow_mut().take_due();
+        let mut actions = Vec::new();
+        let mut errors = Vec::new();
+        for callback in callbacks.into_iter() {
+            match self.evaluate_actions(&list!(callback)) {
+                Ok(a) => actions.extend(a),
+                Err(e) => errors.push(e)
+            }
+        }
+        (actions, errors)
+    }
+    fn time_until_next_timer(&self) -> Option<Duration> {
+        self.timers.borrow().time_until_next()
+    }
+    fn key_bindings(&self) -> Vec<(String, KeyBinding)> {
+        self.key_bindings.borrow().iter().map(|(key_name, binding)| {
+            let binding = match *binding {
+                Datum::String(ref name) => KeyBinding::Action(name.clone()),
+                Datum::Boolean(false) => KeyBinding::Unbound,
+                _ => KeyBinding::Script
+            };
+            (key_name.clone(), binding)
+        }).collect()
+    }
+    fn run_key_binding(&mut self, key_name: &str) ->
+        Result<Vec<ScriptAction>, String>
+    {
+        let binding = self.key_bindings.borrow().get(key_name).cloned();
+        match binding {
+            Some(b) => self.evaluate_actions(&list!(b)),
+            None => Ok(vec![])
+        }
+    }
+    fn set_msdp_variable(&mut self, variable: &str, value: &MsdpValue) {
+        self.msdp_variables.borrow_mut().insert(variable.to_string(),
+            value.clone());
+    }
+    fn clear_msdp_variables(&mut self) {
+        self.msdp_variables.borrow_mut().clear();
+    }
+    fn set_line_links(&mut self, links: Vec<(String, Link)>) {
+        *self.line_links.borrow_mut() = links;
+    }
     fn evaluate(&mut self, s: &str) -> Result<(), String>
     {
         match self.interp.evaluate(s) {
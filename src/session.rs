@@ -1,15 +1,57 @@
+use actions::ServerEvent;
 use indexed::Indexed;
 use tome::{FormattedString, Format, Color, Style, ParseState, RingBuffer,
     SearchResult};
+use tome::telnet::{self, OptionTable};
+use tome::telnet::ttype::{self, TerminalTypes};
+use std::collections::HashMap;
+use std::env;
+use std::time::Instant;
+use tome::telnet::msdp::MsdpValue;
 use mio::tcp::TcpStream;
+use std::io::Write;
+use tome::encoding::{Decoder, Encoding};
+use tome::mccp::{Deflater, Inflater};
+use tome::msp::MspParser;
+use tome::mxp::{LinkTable, MxpParser};
+
+// Capabilities reported to the server through MTTS. These should reflect
+// what the escape sequence handling and the UI can actually display; RGB
+// colors are shown using the nearest of the 256, so truecolor isn't claimed.
+const MTTS_SUPPORT: u32 = ttype::MTTS_ANSI | ttype::MTTS_UTF8 |
+    ttype::MTTS_256_COLORS;
+
+// How many MXP links to remember. Text in the scrollback that refers to an
+// older link is still shown, but the link can't be looked up.
+const MAX_LINKS: usize = 1000;
 
 pub struct Session {
     pub connection: TcpStream,
     pub telnet_state: ParseState,
+    pub options: OptionTable,
+    pub window_size: (u16, u16),
+    pub terminal_types: TerminalTypes,
+    pub inflater: Option<Inflater>,
+    pub deflater: Option<Deflater>,
+    pub msdp_variables: HashMap<String, MsdpValue>,
     pub esc_seq_state: ParseState,
+    pub decoder: Decoder,
+    pub msp: MspParser,
+    pub mxp: MxpParser,
+    // MXP links by the id stored in the format of their text.
+    pub links: LinkTable,
     pub char_format: Format,
     pub scrollback_buf: Indexed<RingBuffer<FormattedString>>,
-    pub prev_search_result: Option<SearchResult>
+    pub prev_search_result: Option<SearchResult>,
+    // The most recent prompt, if one has arrived.
+    pub prompt: Option<FormattedString>,
+    // Text received since the last complete line. It's held back until the
+    // line is complete, or is flushed as a prompt if nothing more arrives.
+    pub partial_line: FormattedString,
+    pub partial_line_time: Option<Instant>,
+    // Events that arrived partway through the partial line, waiting for it
+    // to be finished.
+    pub held_events: Vec<ServerEvent>
 }
 
 impl Session {
@@ -21,17 +63,102 @@ impl Session {
             // can be indexed.
             buffer.data.push(FormattedString::new());
         }
+        // Report the terminal type from the environment, if possible.
+        let terminal_type = env::var("TERM").unwrap_or("ANSI".to_string());
+
         Session {
             connection: connection,
             telnet_state: ParseState::NotInProgress,
+            options: supported_options(),
+            window_size: (0, 0),
+            terminal_types: TerminalTypes::new("TOME", &terminal_type,
+                MTTS_SUPPORT),
+            inflater: None,
+            deflater: None,
+            msdp_variables: HashMap::new(),
             esc_seq_state: ParseState::NotInProgress,
-            char_format: Format {
-                style: Style::Normal,
-                fg_color: Color::Default,
-                bg_color: Color::Default
-            },
+            decoder: Decoder::new(Encoding::Utf8, Encoding::Latin1),
+            msp: MspParser::new(),
+            mxp: MxpParser::new(),
+            links: LinkTable::new(MAX_LINKS),
+            char_format: default_format(),
             scrollback_buf: buffer,
-            prev_search_result: None
+            prev_search_result: None,
+            prompt: None,
+            partial_line: FormattedString::new(),
+            partial_line_time: None,
+            held_events: Vec::new()
         }
     }
+    // Starts over on a new connection. Nothing negotiated with the old one
+    // carries over, so the server can negotiate everything again.
+    pub fn reset(&mut self, connection: TcpStream) {
+        self.connection = connection;
+        self.telnet_state = ParseState::NotInProgress;
+        self.options = supported_options();
+        self.terminal_types.reset();
+        self.inflater = None;
+        self.deflater = None;
+        self.msdp_variables.clear();
+        self.esc_seq_state = ParseState::NotInProgress;
+        self.decoder.set_encoding(Encoding::Utf8);
+        self.msp = MspParser::new();
+        // Links from the old connection are kept along with the scrollback.
+        self.mxp.reset();
+        self.char_format = default_format();
+        self.partial_line.clear();
+        self.partial_line_time = None;
+        self.held_events.clear();
+    }
+    pub fn write(&mut self, data: &[u8]) {
+        // Compress the data first if the server has asked for it.
+        let compressed = match self.deflater {
+            Some(ref mut d) => match d.deflate(data) {
+                Ok(c) => Some(c),
+                Err(e) => {
+                    warn!("Failed to compress outgoing data: {}", e);
+                    return;
+                }
+            },
+            None => None
+        };
+
+        // TODO: Check result.
+        match compressed {
+            Some(c) => self.connection.write(&c),
+            None => self.connection.write(data)
+        };
+    }
+}
+
+// Helper function to set up the telnet options that will be accepted from the
+// server.
+fn supported_options() -> OptionTable {
+    let mut options = OptionTable::new();
+    options.support_local(telnet::BINARY);
+    options.support_remote(telnet::BINARY);
+    options.support_remote(telnet::ECHO);
+    options.support_remote(telnet::SGA);
+    options.support_remote(telnet::TELOPT_EOR);
+    options.support_remote(telnet::GMCP);
+    options.support_remote(telnet::MSDP);
+    options.support_remote(telnet::MSP);
+    options.support_remote(telnet::MXP);
+    options.support_local(telnet::NAWS);
+    options.support_local(telnet::TTYPE);
+    options.support_local(telnet::CHARSET);
+    options.support_remote(telnet::CHARSET);
+    options.support_remote(telnet::COMPRESS2);
+    options.support_remote(telnet::COMPRESS3);
+    options
+}
+
+// Helper function to get the format text starts out with.
+fn default_format() -> Format {
+    Format {
+        style: Style::normal(),
+        fg_color: Color::Default,
+        bg_color: Color::Default,
+        link: None
+    }
 }
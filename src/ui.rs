@@ -1,52 +1,140 @@
 extern crate ncurses;
 
-use tome::{FormattedString, Format, Color, Style};
-
-static BLACK_ON_DEFAULT_BG: i16 = 1;
-static RED_ON_DEFAULT_BG: i16 = 2;
-static GREEN_ON_DEFAULT_BG: i16 = 3;
-static YELLOW_ON_DEFAULT_BG: i16 = 4;
-static BLUE_ON_DEFAULT_BG: i16 = 5;
-static MAGENTA_ON_DEFAULT_BG: i16 = 6;
-static CYAN_ON_DEFAULT_BG: i16 = 7;
-static WHITE_ON_DEFAULT_BG: i16 = 8;
-static INPUT_LINE_COLOR_PAIR: i16 = 9;
-
-fn convert_char(ch: char, format: Format) -> ncurses::chtype {
-    // Handle the fg color.
-    let mut out_char = ch as ncurses::chtype;
-    match format.fg_color {
-        Color::Black => out_char = out_char | ncurses::COLOR_PAIR(BLACK_ON_DEFAULT_BG),
-        Color::Red => out_char = out_char | ncurses::COLOR_PAIR(RED_ON_DEFAULT_BG),
-        Color::Green => out_char = out_char | ncurses::COLOR_PAIR(GREEN_ON_DEFAULT_BG),
-        Color::Yellow => out_char = out_char | ncurses::COLOR_PAIR(YELLOW_ON_DEFAULT_BG),
-        Color::Blue => out_char = out_char | ncurses::COLOR_PAIR(BLUE_ON_DEFAULT_BG),
-        Color::Magenta => out_char = out_char |
-            ncurses::COLOR_PAIR(MAGENTA_ON_DEFAULT_BG),
-        Color::Cyan => out_char = out_char | ncurses::COLOR_PAIR(CYAN_ON_DEFAULT_BG),
-        Color::White => out_char = out_char | ncurses::COLOR_PAIR(WHITE_ON_DEFAULT_BG),
-        _ => ()
-    };
-
-    // TODO: Handle the bg color.
-
-    // Handle the style.
-    match format.style {
-        Style::Normal => out_char = out_char | ncurses::A_NORMAL(),
-        Style::Bold => out_char = out_char | ncurses::A_BOLD(),
-        Style::Standout => out_char = out_char | ncurses::A_REVERSE(),
+use std::cmp;
+use std::collections::HashMap;
+use std::i16;
+use tome::{Color, FormattedString, Format};
+
+static INPUT_LINE_COLOR_PAIR: i16 = 1;
+
+// Color pairs from here on are handed out as needed.
+static FIRST_DYNAMIC_COLOR_PAIR: i16 = 2;
+
+// Added after a character to strike it out.
+const COMBINING_STRIKETHROUGH: char = '\u{0336}';
+
+// Color pairs for the (fg, bg) combinations in use. There are usually far
+// fewer pairs than combinations, so the least recently used pair is reused
+// once they run out.
+struct ColorPairs {
+    // The number of colors that can be displayed.
+    num_colors: u32,
+    max_pairs: usize,
+    // Maps terminal (fg, bg) colors to the pair and the update it was last
+    // used in.
+    pairs: HashMap<(i16, i16), (i16, u64)>,
+    update_count: u64
+}
+
+impl ColorPairs {
+    fn new() -> ColorPairs {
+        let num_colors = if ncurses::has_colors() {
+            cmp::min(ncurses::COLORS(), 256)
+        } else {
+            0
+        };
+        let num_colors = if num_colors >= 256 {256}
+            else if num_colors >= 16 {16}
+            else if num_colors >= 8 {8}
+            else {0};
+
+        // Pair numbers above 255 rely on ncurses' extended color support.
+        let max_pairs = cmp::min(ncurses::COLOR_PAIRS(), i16::MAX as i32) -
+            FIRST_DYNAMIC_COLOR_PAIR as i32;
+        ColorPairs {
+            num_colors: num_colors,
+            max_pairs: cmp::max(max_pairs, 0) as usize,
+            pairs: HashMap::new(),
+            update_count: 0
+        }
+    }
+
+    // Marks the start of a UI update. Pairs used during an update aren't
+    // reused until the next one, since that would change the colors of text
+    // already written.
+    fn start_update(&mut self) {
+        self.update_count += 1;
+    }
+
+    // Gets the pair to use for the given colors, using the closest colors the
+    // terminal has.
+    fn get(&mut self, fg: Color, bg: Color) -> i16 {
+        let num_colors = self.num_colors;
+        let to_terminal = |c: Color| match c.nearest_index(num_colors) {
+            Some(i) if num_colors > 0 => i as i16,
+            _ => -1
+        };
+        let key = (to_terminal(fg), to_terminal(bg));
+        if key == (-1, -1) {
+            return 0;
+        }
+
+        let update_count = self.update_count;
+        if let Some(entry) = self.pairs.get_mut(&key) {
+            entry.1 = update_count;
+            return entry.0;
+        }
+
+        let pair = if self.pairs.len() < self.max_pairs {
+            FIRST_DYNAMIC_COLOR_PAIR + self.pairs.len() as i16
+        } else {
+            // Reuse the least recently used pair.
+            let oldest = self.pairs.iter()
+                .min_by_key(|&(_, &(_, used))| used)
+                .map(|(k, &(p, used))| (*k, p, used));
+            match oldest {
+                Some((old_key, p, used)) if used < update_count => {
+                    self.pairs.remove(&old_key);
+                    p
+                },
+                _ => return 0 // Every pair is on the screen already.
+            }
+        };
+        ncurses::init_pair(pair, key.0, key.1);
+        self.pairs.insert(key, (pair, update_count));
+        pair
+    }
+}
+
+fn convert_format(format: Format, color_pairs: &mut ColorPairs) ->
+    (ncurses::attr_t, i16)
+{
+    // Handle the colors.
+    let mut attrs = 0;
+    let pair = color_pairs.get(format.fg_color, format.bg_color);
+
+    // Handle the style. There is no attribute for strikethrough, so that is
+    // handled when the character is written.
+    let style = format.style;
+    if style.bold { attrs = attrs | ncurses::A_BOLD(); }
+    if style.dim { attrs = attrs | ncurses::A_DIM(); }
+    if style.italic { attrs = attrs | ncurses::A_ITALIC(); }
+    if style.underline { attrs = attrs | ncurses::A_UNDERLINE(); }
+    if style.blink { attrs = attrs | ncurses::A_BLINK(); }
+    if style.reverse { attrs = attrs | ncurses::A_REVERSE(); }
+
+    // Underline links so they stand out.
+    if format.link.is_some() {
+        attrs = attrs | ncurses::A_UNDERLINE();
     }
 
-    return out_char;
+    return (attrs, pair);
 }
 
 pub struct UserInterface {
     output_win: ncurses::WINDOW,
-    input_win: ncurses::WINDOW
+    prompt_win: ncurses::WINDOW,
+    input_win: ncurses::WINDOW,
+    color_pairs: ColorPairs,
+    // The prompt line takes a row only once there's a prompt to show.
+    show_prompt: bool
 }
 
 impl UserInterface {
     pub fn init() -> UserInterface {
+        // Use the locale from the environment so that non-ASCII characters
+        // can be displayed.
+        ncurses::setlocale(ncurses::LcCategory::all, "");
         ncurses::initscr();
         ncurses::keypad(ncurses::stdscr(), true);
         ncurses::cbreak();
@@ -55,14 +143,6 @@ impl UserInterface {
         // Init colors.
         ncurses::start_color();
         ncurses::use_default_colors();
-        ncurses::init_pair(BLACK_ON_DEFAULT_BG, 0, -1);
-        ncurses::init_pair(RED_ON_DEFAULT_BG, 1, -1);
-        ncurses::init_pair(GREEN_ON_DEFAULT_BG, 2, -1);
-        ncurses::init_pair(YELLOW_ON_DEFAULT_BG, 3, -1);
-        ncurses::init_pair(BLUE_ON_DEFAULT_BG, 4, -1);
-        ncurses::init_pair(MAGENTA_ON_DEFAULT_BG, 5, -1);
-        ncurses::init_pair(CYAN_ON_DEFAULT_BG, 6, -1);
-        ncurses::init_pair(WHITE_ON_DEFAULT_BG, 7, -1);
         ncurses::init_pair(INPUT_LINE_COLOR_PAIR, 0, 6);
 
         let ui_width = UserInterface::width() as i32;
@@ -70,12 +150,16 @@ impl UserInterface {
         let output_win = ncurses::newwin(ui_height - 1, ui_width, 0, 0);
         ncurses::scrollok(output_win, true);
         ncurses::keypad(output_win, true); 
+        let prompt_win = ncurses::newwin(1, ui_width, ui_height - 2, 0);
         let input_win = ncurses::newwin(1, ui_width, ui_height - 1, 0);
         ncurses::keypad(input_win, true); 
         ncurses::wbkgd(input_win, ncurses::COLOR_PAIR(INPUT_LINE_COLOR_PAIR));
         UserInterface {
             output_win: output_win,
-            input_win: input_win
+            prompt_win: prompt_win,
+            input_win: input_win,
+            color_pairs: ColorPairs::new(),
+            show_prompt: false
         }
     }
     pub fn restart(&mut self) {
@@ -89,45 +173,106 @@ impl UserInterface {
 
         // Set up the new windows.
         self.input_win = new_ui.input_win;
+        self.prompt_win = new_ui.prompt_win;
         self.output_win = new_ui.output_win;
+        self.color_pairs = new_ui.color_pairs;
+        if self.show_prompt {
+            self.fit_output_win();
+        }
+    }
+    // Shows or hides the prompt line, taking its row from the output window
+    // or giving it back. Returns whether the layout changed.
+    pub fn show_prompt(&mut self, show: bool) -> bool {
+        if show == self.show_prompt {
+            return false;
+        }
+        self.show_prompt = show;
+        self.fit_output_win();
+        true
+    }
+    // Helper function to give the output window the rows the other windows
+    // leave.
+    fn fit_output_win(&self) {
+        let rows = UserInterface::height() as i32 - if self.show_prompt {2} else {1};
+        ncurses::wresize(self.output_win, rows, UserInterface::width() as i32);
     }
     pub fn teardown(&mut self) {
         ncurses::delwin(self.input_win);
+        ncurses::delwin(self.prompt_win);
         ncurses::delwin(self.output_win);
         ncurses::endwin();
     }
     pub fn update<'a, I: Iterator<Item=&'a FormattedString>>(&mut self,
         output_lines: I,
+        prompt: Option<&FormattedString>,
         input_line: I,
+        mask_input: bool,
         cursor_index: usize)
     {
+        self.color_pairs.start_update();
+
         // Write the output buffer.
+        let output_win_height = self.output_win_height();
         ncurses::werase(self.output_win);
         UserInterface::write_lines_to_window(
-            &self.output_win, output_lines.take(self.output_win_height()));
+            &self.output_win, &mut self.color_pairs,
+            output_lines.take(output_win_height));
         ncurses::wrefresh(self.output_win);
 
+        // Write the most recent prompt.
+        if self.show_prompt {
+            ncurses::werase(self.prompt_win);
+            UserInterface::write_lines_to_window(&self.prompt_win,
+                &mut self.color_pairs, prompt.into_iter());
+            ncurses::wrefresh(self.prompt_win);
+        }
+
         // Write the input line.
         ncurses::werase(self.input_win);
-        UserInterface::write_lines_to_window(
-            &self.input_win, input_line.take(1));
+        if mask_input {
+            // Hide the input (e.g. for passwords).
+            let masked: Vec<FormattedString> = input_line.take(1)
+                .map(|line| line.iter().map(|(_, format)| ('*', format)).collect())
+                .collect();
+            UserInterface::write_lines_to_window(
+                &self.input_win, &mut self.color_pairs, masked.iter());
+        } else {
+            UserInterface::write_lines_to_window(
+                &self.input_win, &mut self.color_pairs, input_line.take(1));
+        }
         ncurses::wmove(self.input_win, 0, cursor_index as i32);
         ncurses::wrefresh(self.input_win);
     }
     fn write_lines_to_window<'a, I: Iterator<Item=&'a FormattedString>>(
-        win: &ncurses::WINDOW, lines: I)
+        win: &ncurses::WINDOW, color_pairs: &mut ColorPairs, lines: I)
     {
         for (i, line) in lines.enumerate() {
             if i > 0 {
                 ncurses::waddch(*win, 0xA);
             }
-            for &(ch, format) in line.iter() {
-                ncurses::waddch(*win, convert_char(ch, format));
+            for (text, format) in line.spans() {
+                // The attributes are set on the window rather than combined
+                // with the characters, since pair numbers can be too large to
+                // fit in a chtype.
+                let (attrs, pair) = convert_format(format, color_pairs);
+                ncurses::wattr_set(*win, attrs, pair);
+                if format.style.strikethrough {
+                    let struck: String = text.chars()
+                        .flat_map(|ch| vec![ch, COMBINING_STRIKETHROUGH])
+                        .collect();
+                    ncurses::waddstr(*win, &struck);
+                } else {
+                    ncurses::waddstr(*win, text);
+                }
             }
+            ncurses::wattr_set(*win, ncurses::A_NORMAL(), 0);
         }
     }
     pub fn width() -> usize { Self::win_width(ncurses::stdscr()) }
     pub fn height() -> usize { Self::win_height(ncurses::stdscr()) }
+    pub fn output_win_width(&self) -> usize {
+        Self::win_width(self.output_win)
+    }
     pub fn output_win_height(&self) -> usize {
         Self::win_height(self.output_win)
     }